@@ -0,0 +1,92 @@
+//! Raw C ABI shared by iaue and plugin libraries.
+//!
+//! A plugin exports two symbols, `iaue_abi_version` and `iaue_descriptor`.
+//! Every buffer is allocated by the caller and passed as pointer and length.
+//!
+//! Version 2 changed what `EffectBlock::sidechains` holds: a track keyed with `:sidechain` only gets its keys,
+//! in version 1 every effect got every track.
+use std::ffi::{c_char, c_void};
+
+pub const ABI_VERSION: u32 = 2;
+
+pub const VERSION_SYMBOL: &[u8] = b"iaue_abi_version";
+pub const DESCRIPTOR_SYMBOL: &[u8] = b"iaue_descriptor";
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Frame {
+    pub left: f32,
+    pub right: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSlice {
+    pub ptr: *const Frame,
+    pub len: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NoteInfo {
+    pub freq: f32,
+    /// Length in seconds.
+    pub len: f32,
+    pub vel: f32,
+    pub sample_rate: u32,
+    /// Index of the first requested frame counted from the start of the note.
+    pub start: u64,
+    pub params: *const f32,
+    pub params_len: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct EffectBlock {
+    pub input: *const Frame,
+    pub output: *mut Frame,
+    pub len: usize,
+    /// Output of the tracks this track is keyed from in the order of the project routes,
+    /// or of every track indexed like the tracks of the project when it has no keys.
+    pub sidechains: *const FrameSlice,
+    pub sidechains_len: usize,
+}
+
+/// Fills `out` with up to `out_len` frames and returns how many were written.
+pub type InstrumentFn = unsafe extern "C" fn(note: *const NoteInfo, out: *mut Frame, out_len: usize) -> usize;
+pub type EffectCreateFn = unsafe extern "C" fn(sample_rate: u32, params: *const f32, params_len: usize) -> *mut c_void;
+pub type EffectProcessFn = unsafe extern "C" fn(state: *mut c_void, block: *const EffectBlock);
+pub type EffectDestroyFn = unsafe extern "C" fn(state: *mut c_void);
+
+pub type VersionFn = unsafe extern "C" fn() -> u32;
+pub type DescriptorFn = unsafe extern "C" fn() -> *const Descriptor;
+
+#[repr(C)]
+pub struct InstrumentEntry {
+    pub id: u32,
+    pub name: *const c_char,
+    pub render: InstrumentFn,
+}
+
+#[repr(C)]
+pub struct EffectEntry {
+    pub id: u32,
+    pub name: *const c_char,
+    pub create: EffectCreateFn,
+    pub process: EffectProcessFn,
+    pub destroy: EffectDestroyFn,
+}
+
+#[repr(C)]
+pub struct Descriptor {
+    pub abi_version: u32,
+    pub instruments: *const InstrumentEntry,
+    pub instruments_len: usize,
+    pub effects: *const EffectEntry,
+    pub effects_len: usize,
+}
+
+// Entries only point to static names and functions.
+unsafe impl Sync for InstrumentEntry {}
+unsafe impl Sync for EffectEntry {}
+unsafe impl Sync for Descriptor {}
@@ -0,0 +1,177 @@
+//! Helpers for writing iaue instruments and effects without touching the raw ABI.
+//!
+//! ```no_run
+//! use iaue_plugin::{Effect, Frame, Note};
+//!
+//! fn sine(note: &Note, out: &mut [Frame]) {
+//!     for (i, frame) in out.iter_mut().enumerate() {
+//!         let t = (note.start + i as u64) as f32 / note.sample_rate as f32;
+//!         let s = (t * note.freq * std::f32::consts::TAU).sin() * note.vel;
+//!         *frame = Frame { left: s, right: s };
+//!     }
+//! }
+//!
+//! struct Gain(f32);
+//!
+//! impl Effect for Gain {
+//!     fn new(_sample_rate: u32, params: &[f32]) -> Self {
+//!         Gain(params.first().copied().unwrap_or(1.0))
+//!     }
+//!     fn process(&mut self, input: &[Frame], output: &mut [Frame], _sidechains: &[&[Frame]]) {
+//!         for (o, i) in output.iter_mut().zip(input) {
+//!             *o = Frame { left: i.left * self.0, right: i.right * self.0 };
+//!         }
+//!     }
+//! }
+//!
+//! iaue_plugin::export! {
+//!     instruments: [1 => sine],
+//!     effects: [1 => Gain],
+//! }
+//! ```
+//!
+//! Since ABI version 2 the `sidechains` of an effect are only the tracks its track is keyed from,
+//! libraries built against version 1 are refused and have to be rebuilt.
+pub mod abi;
+
+pub use abi::Frame;
+use std::ffi::c_void;
+
+pub struct Note<'a> {
+    pub freq: f32,
+    /// Length in seconds.
+    pub len: f32,
+    pub vel: f32,
+    pub sample_rate: u32,
+    /// Index of `out[0]` counted from the start of the note.
+    pub start: u64,
+    /// Values sent with note fx `3`.
+    pub params: &'a [f32],
+}
+
+impl Note<'_> {
+    /// Length of the whole note in frames.
+    pub fn frames(&self) -> usize {
+        (self.len * self.sample_rate as f32) as usize
+    }
+}
+
+pub trait Effect {
+    fn new(sample_rate: u32, params: &[f32]) -> Self;
+    /// `sidechains` are the tracks the track is keyed from with `:sidechain`, after their fx and mixer strip,
+    /// in the order of the routes. A track without keys gets every track of the project indexed like its columns.
+    fn process(&mut self, input: &[Frame], output: &mut [Frame], sidechains: &[&[Frame]]);
+}
+
+unsafe fn slice<'a, T>(ptr: *const T, len: usize) -> &'a [T] {
+    if ptr.is_null() || len == 0 { &[] } else { std::slice::from_raw_parts(ptr, len) }
+}
+
+#[doc(hidden)]
+pub unsafe fn call_instrument(
+    render: fn(&Note, &mut [Frame]),
+    note: *const abi::NoteInfo,
+    out: *mut Frame,
+    out_len: usize,
+) -> usize {
+    if note.is_null() || out.is_null() {
+        return 0;
+    }
+    let info = &*note;
+    let note = Note {
+        freq: info.freq,
+        len: info.len,
+        vel: info.vel,
+        sample_rate: info.sample_rate,
+        start: info.start,
+        params: slice(info.params, info.params_len),
+    };
+    let out = std::slice::from_raw_parts_mut(out, out_len);
+    let len = note.frames().saturating_sub(note.start as usize).min(out_len);
+    render(&note, &mut out[..len]);
+    len
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn create_effect<E: Effect>(sample_rate: u32, params: *const f32, params_len: usize) -> *mut c_void {
+    Box::into_raw(Box::new(E::new(sample_rate, slice(params, params_len)))) as *mut c_void
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn process_effect<E: Effect>(state: *mut c_void, block: *const abi::EffectBlock) {
+    if state.is_null() || block.is_null() {
+        return;
+    }
+    let block = &*block;
+    if block.output.is_null() {
+        return;
+    }
+    let input = slice(block.input, block.len);
+    let output = std::slice::from_raw_parts_mut(block.output, block.len);
+    let sidechains = slice(block.sidechains, block.sidechains_len)
+        .iter()
+        .map(|it| slice(it.ptr, it.len))
+        .collect::<Vec<_>>();
+    (*(state as *mut E)).process(input, output, &sidechains);
+}
+
+#[doc(hidden)]
+pub unsafe extern "C" fn destroy_effect<E: Effect>(state: *mut c_void) {
+    if !state.is_null() {
+        drop(Box::from_raw(state as *mut E));
+    }
+}
+
+/// Exports the ABI symbols for the listed instrument functions and effect types.
+#[macro_export]
+macro_rules! export {
+    (
+        instruments: [$($iid:literal => $ifn:path),* $(,)?],
+        effects: [$($eid:literal => $ety:ty),* $(,)?] $(,)?
+    ) => {
+        const _: () = {
+            const INSTRUMENTS: &[$crate::abi::InstrumentEntry] = &[$(
+                $crate::abi::InstrumentEntry {
+                    id: $iid,
+                    name: concat!(stringify!($ifn), "\0").as_ptr() as *const ::std::ffi::c_char,
+                    render: {
+                        unsafe extern "C" fn render(
+                            note: *const $crate::abi::NoteInfo,
+                            out: *mut $crate::Frame,
+                            out_len: usize,
+                        ) -> usize {
+                            unsafe { $crate::call_instrument($ifn, note, out, out_len) }
+                        }
+                        render
+                    },
+                }
+            ),*];
+            const EFFECTS: &[$crate::abi::EffectEntry] = &[$(
+                $crate::abi::EffectEntry {
+                    id: $eid,
+                    name: concat!(stringify!($ety), "\0").as_ptr() as *const ::std::ffi::c_char,
+                    create: $crate::create_effect::<$ety>,
+                    process: $crate::process_effect::<$ety>,
+                    destroy: $crate::destroy_effect::<$ety>,
+                }
+            ),*];
+            static DESCRIPTOR: $crate::abi::Descriptor = $crate::abi::Descriptor {
+                abi_version: $crate::abi::ABI_VERSION,
+                instruments: INSTRUMENTS.as_ptr(),
+                instruments_len: INSTRUMENTS.len(),
+                effects: EFFECTS.as_ptr(),
+                effects_len: EFFECTS.len(),
+            };
+
+            #[no_mangle]
+            pub extern "C" fn iaue_abi_version() -> u32 {
+                $crate::abi::ABI_VERSION
+            }
+
+            #[no_mangle]
+            pub extern "C" fn iaue_descriptor() -> *const $crate::abi::Descriptor {
+                &DESCRIPTOR
+            }
+        };
+    };
+}
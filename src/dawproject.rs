@@ -0,0 +1,184 @@
+use crate::midi;
+use crate::project::Project;
+use crate::zip::ZipWriter;
+use std::fmt::Write;
+use std::path::Path;
+
+// Offsets under this many semitones are left out of the per note transpose.
+const MIN_OFFSET: f32 = 0.001;
+
+/// Writes a DAWproject archive with the tempo and one note track per column, every track holds one clip.
+/// Frequencies between keys get a per note transpose expression. Returns the notes written and skipped like MIDI export.
+pub fn export(project: &Project, path: &Path) -> Result<(usize, usize), String> {
+    let beats_per_frame = project.settings.tempo as f64 / 60.0 / project.settings.sample_rate as f64;
+    let (mut written, mut skipped) = (0, 0);
+    let mut ids = 0..;
+    let mut id = || format!("id{}", ids.next().unwrap_or(0));
+
+    let master = id();
+    let mut structure = String::new();
+    let mut lanes = String::new();
+    for (i, track) in project.tracks.iter().enumerate() {
+        let (track_id, channel_id) = (id(), id());
+        let _ = write!(
+            structure,
+            r#"    <Track contentType="notes" loaded="true" id="{}" name="{}">
+      <Channel audioChannels="2" destination="{}" role="regular" solo="false" id="{}">
+        <Volume max="2.0" min="0.0" unit="linear" value="1.0" id="{}" name="Volume"/>
+        <Pan max="1.0" min="0.0" unit="normalized" value="0.5" id="{}" name="Pan"/>
+      </Channel>
+    </Track>
+"#,
+            track_id,
+            escape(&track.name),
+            master,
+            channel_id,
+            id(),
+            id(),
+        );
+        let (notes, out_of_range) = midi::key_notes(project, i);
+        skipped += out_of_range;
+        written += notes.len();
+        let end = notes.iter().map(|it| it.start + it.frames).max().unwrap_or(0) as f64 * beats_per_frame;
+        let _ = write!(
+            lanes,
+            r#"      <Lanes track="{}" id="{}">
+        <Clips id="{}">
+          <Clip time="0.0" duration="{}" playStart="0.0">
+            <Notes id="{}">
+"#,
+            track_id,
+            id(),
+            id(),
+            end,
+            id(),
+        );
+        for note in notes {
+            let (time, duration) = (note.start as f64 * beats_per_frame, note.frames as f64 * beats_per_frame);
+            let attributes = format!(
+                r#"time="{}" duration="{}" channel="0" key="{}" vel="{}" rel="0.5""#,
+                time,
+                duration,
+                note.key,
+                note.vel.min(1.0)
+            );
+            if note.offset.abs() < MIN_OFFSET {
+                let _ = writeln!(lanes, "              <Note {}/>", attributes);
+            } else {
+                let _ = write!(
+                    lanes,
+                    r#"              <Note {}>
+                <Points unit="semitones" id="{}">
+                  <Target expression="transpose"/>
+                  <RealPoint time="0.0" value="{}"/>
+                </Points>
+              </Note>
+"#,
+                    attributes,
+                    id(),
+                    note.offset,
+                );
+            }
+        }
+        lanes.push_str("            </Notes>\n          </Clip>\n        </Clips>\n      </Lanes>\n");
+    }
+
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Project version="1.0">
+  <Application name="iaue" version="{}"/>
+  <Transport>
+    <Tempo max="666.0" min="20.0" unit="bpm" value="{}" id="{}" name="Tempo"/>
+    <TimeSignature denominator="4" numerator="4" id="{}"/>
+  </Transport>
+  <Structure>
+{}    <Track contentType="audio notes" loaded="true" id="{}" name="Master">
+      <Channel audioChannels="2" role="master" solo="false" id="{}">
+        <Volume max="2.0" min="0.0" unit="linear" value="1.0" id="{}" name="Volume"/>
+        <Pan max="1.0" min="0.0" unit="normalized" value="0.5" id="{}" name="Pan"/>
+      </Channel>
+    </Track>
+  </Structure>
+  <Arrangement id="{}">
+    <Lanes timeUnit="beats" id="{}">
+{}    </Lanes>
+  </Arrangement>
+</Project>
+"#,
+        env!("CARGO_PKG_VERSION"),
+        project.settings.tempo,
+        id(),
+        id(),
+        structure,
+        id(),
+        master,
+        id(),
+        id(),
+        id(),
+        id(),
+        lanes,
+    );
+    let metadata = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<MetaData>
+  <Title>{}</Title>
+  <Artist>{}</Artist>
+  <Comment>{}</Comment>
+</MetaData>
+"#,
+        escape(&project.metadata.title),
+        escape(&project.metadata.author),
+        escape(&project.metadata.comment),
+    );
+
+    let mut zip = ZipWriter::default();
+    zip.add("metadata.xml", metadata.as_bytes());
+    zip.add("project.xml", xml.as_bytes());
+    std::fs::write(path, zip.finish()).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+    Ok((written, skipped))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::{NoteRow, Ratio, Track};
+
+    #[test]
+    fn writes_tracks_tempo_and_transpose() {
+        let fifth = NoteRow { freq: Ratio::new(3.0, 2.0), ..NoteRow::default() };
+        let tracks = vec![Track { notes: vec![NoteRow::default(), fifth], ..Track::default() }, Track::default()];
+        let mut project = Project { tracks, ..Project::default() };
+        project.settings.tempo = 90.0;
+        let path = std::env::temp_dir().join(format!("iaue-dawproject-{}.dawproject", std::process::id()));
+        assert_eq!(export(&project, &path).unwrap(), (3, 0));
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // Entries are stored, so project.xml follows its local header as is.
+        let mut pos = 0;
+        let xml = loop {
+            let size = u32::from_le_bytes(data[pos + 18..pos + 22].try_into().unwrap()) as usize;
+            let name_len = u16::from_le_bytes([data[pos + 26], data[pos + 27]]) as usize;
+            let start = pos + 30 + name_len;
+            if &data[pos + 30..start] == b"project.xml" {
+                break String::from_utf8(data[start..start + size].to_vec()).unwrap();
+            }
+            pos = start + size;
+        };
+        assert_eq!(xml.matches(r#"<Track contentType="notes""#).count(), 2);
+        assert!(xml.contains(r#"unit="bpm" value="90""#));
+        // 660 Hz is 0.0196 semitones above key 76, the notes on 440 Hz have no transpose.
+        assert_eq!(xml.matches(r#"<Target expression="transpose"/>"#).count(), 1);
+        let point = xml.split(r#"<RealPoint time="0.0" value=""#).nth(1).unwrap();
+        let offset = point[..point.find('"').unwrap()].parse::<f32>().unwrap();
+        assert!((offset - 0.0196).abs() < 1e-3);
+    }
+}
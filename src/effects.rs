@@ -0,0 +1,330 @@
+use iaue_plugin::Frame;
+use std::collections::VecDeque;
+
+// Ids from 1000 on are reserved for effects built into iaue, user libraries use the ones below.
+pub const GAIN: u32 = 1000;
+pub const PAN: u32 = 1001;
+pub const LOWPASS: u32 = 1002;
+pub const HIGHPASS: u32 = 1003;
+pub const BANDPASS: u32 = 1004;
+pub const DELAY: u32 = 1005;
+pub const REVERB: u32 = 1006;
+pub const COMPRESSOR: u32 = 1007;
+pub const LIMITER: u32 = 1008;
+pub const BITCRUSH: u32 = 1009;
+
+pub enum BuiltinEffect {
+    Gain(f32),
+    Pan { left: f32, right: f32 },
+    Filter([Biquad; 2]),
+    Delay(Delay),
+    Reverb(Box<Reverb>),
+    Compressor(Compressor),
+    Bitcrush(Bitcrush),
+}
+
+impl BuiltinEffect {
+    /// Returns the built-in effect with `id` set up from the params of the track init row.
+    pub fn new(id: u32, sample_rate: usize, params: &[f32]) -> Option<Self> {
+        let sample_rate = sample_rate as f32;
+        Some(match id {
+            GAIN => BuiltinEffect::Gain(db_to_gain(param(params, 0, 0.0))),
+            PAN => {
+                // Constant power, 0 keeps both channels at -3 dB.
+                let angle = (param(params, 0, 0.0).clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+                BuiltinEffect::Pan { left: angle.cos(), right: angle.sin() }
+            }
+            LOWPASS | HIGHPASS | BANDPASS => {
+                let filter = Biquad::new(id, sample_rate, param(params, 0, 1000.0), param(params, 1, 0.707));
+                BuiltinEffect::Filter([filter.clone(), filter])
+            }
+            DELAY => BuiltinEffect::Delay(Delay::new(
+                sample_rate,
+                param(params, 0, 0.25),
+                param(params, 1, 0.4),
+                param(params, 2, 0.3),
+            )),
+            REVERB => BuiltinEffect::Reverb(Box::new(Reverb::new(
+                sample_rate,
+                param(params, 0, 0.5),
+                param(params, 1, 0.5),
+                param(params, 2, 0.3),
+            ))),
+            COMPRESSOR => BuiltinEffect::Compressor(Compressor::new(
+                sample_rate,
+                param(params, 0, -12.0),
+                param(params, 1, 4.0),
+                param(params, 2, 10.0),
+                param(params, 3, 100.0),
+                param(params, 4, 0.0),
+            )),
+            LIMITER => BuiltinEffect::Compressor(Compressor::new(
+                sample_rate,
+                param(params, 0, -0.3),
+                f32::INFINITY,
+                0.0,
+                param(params, 1, 50.0),
+                0.0,
+            )),
+            BITCRUSH => BuiltinEffect::Bitcrush(Bitcrush {
+                levels: 2f32.powf(param(params, 0, 8.0).clamp(1.0, 24.0) - 1.0),
+                hold: (param(params, 1, 1.0) as usize).max(1),
+                counter: 0,
+                held: Frame::default(),
+            }),
+            _ => return None,
+        })
+    }
+
+    pub fn process(&mut self, input: &[Frame], output: &mut [Frame]) {
+        for (out, frame) in output.iter_mut().zip(input) {
+            *out = match self {
+                BuiltinEffect::Gain(gain) => Frame { left: frame.left * *gain, right: frame.right * *gain },
+                BuiltinEffect::Pan { left, right } => Frame { left: frame.left * *left, right: frame.right * *right },
+                BuiltinEffect::Filter([left, right]) => Frame { left: left.process(frame.left), right: right.process(frame.right) },
+                BuiltinEffect::Delay(delay) => delay.process(*frame),
+                BuiltinEffect::Reverb(reverb) => reverb.process(*frame),
+                BuiltinEffect::Compressor(compressor) => compressor.process(*frame),
+                BuiltinEffect::Bitcrush(crush) => crush.process(*frame),
+            };
+        }
+    }
+}
+
+fn param(params: &[f32], i: usize, default: f32) -> f32 {
+    params.get(i).copied().unwrap_or(default)
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Low, high or band pass from the RBJ audio EQ cookbook.
+#[derive(Clone)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    fn new(kind: u32, sample_rate: f32, cutoff: f32, q: f32) -> Self {
+        let w0 = 2.0 * std::f32::consts::PI * cutoff.clamp(1.0, (sample_rate * 0.49).max(1.0)) / sample_rate;
+        let alpha = w0.sin() / (2.0 * q.max(0.01));
+        let cos = w0.cos();
+        let (b0, b1, b2) = match kind {
+            HIGHPASS => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0),
+            BANDPASS => (alpha, 0.0, -alpha),
+            _ => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0),
+        };
+        let a0 = 1.0 + alpha;
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let out = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * out + self.z2;
+        self.z2 = self.b2 * input - self.a2 * out;
+        out
+    }
+}
+
+pub struct Delay {
+    buffer: VecDeque<Frame>,
+    feedback: f32,
+    mix: f32,
+}
+
+impl Delay {
+    fn new(sample_rate: f32, time: f32, feedback: f32, mix: f32) -> Self {
+        let len = ((time.max(0.0) * sample_rate) as usize).max(1);
+        Delay { buffer: vec![Frame::default(); len].into(), feedback: feedback.clamp(-0.99, 0.99), mix }
+    }
+
+    fn process(&mut self, input: Frame) -> Frame {
+        let delayed = self.buffer.pop_front().unwrap_or_default();
+        self.buffer.push_back(Frame {
+            left: input.left + delayed.left * self.feedback,
+            right: input.right + delayed.right * self.feedback,
+        });
+        mix(input, delayed, self.mix)
+    }
+}
+
+fn mix(dry: Frame, wet: Frame, amount: f32) -> Frame {
+    Frame {
+        left: dry.left * (1.0 - amount) + wet.left * amount,
+        right: dry.right * (1.0 - amount) + wet.right * amount,
+    }
+}
+
+struct Comb {
+    buffer: Vec<f32>,
+    pos: usize,
+    store: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let out = self.buffer[self.pos];
+        self.store = out * (1.0 - damp) + self.store * damp;
+        self.buffer[self.pos] = input + self.store * feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        out
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.pos];
+        self.buffer[self.pos] = input + buffered * 0.5;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// Freeverb: parallel combs into serial allpasses per channel, the right one is detuned for width.
+pub struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+    feedback: f32,
+    damp: f32,
+    mix: f32,
+}
+
+impl Reverb {
+    // Delay lengths in frames at 44.1 kHz.
+    const COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+    const ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+    const SPREAD: usize = 23;
+
+    fn new(sample_rate: f32, room: f32, damping: f32, mix: f32) -> Self {
+        let scale = |len: usize, spread: usize| (((len + spread) as f32 * sample_rate / 44100.0) as usize).max(1);
+        let combs = [0, Self::SPREAD].map(|spread| {
+            Self::COMBS
+                .iter()
+                .map(|&len| Comb { buffer: vec![0.0; scale(len, spread)], pos: 0, store: 0.0 })
+                .collect()
+        });
+        let allpasses = [0, Self::SPREAD].map(|spread| {
+            Self::ALLPASSES
+                .iter()
+                .map(|&len| Allpass { buffer: vec![0.0; scale(len, spread)], pos: 0 })
+                .collect()
+        });
+        Reverb {
+            combs,
+            allpasses,
+            feedback: room.clamp(0.0, 1.0) * 0.28 + 0.7,
+            damp: damping.clamp(0.0, 1.0) * 0.4,
+            mix,
+        }
+    }
+
+    fn process(&mut self, input: Frame) -> Frame {
+        let mono = (input.left + input.right) * 0.015;
+        let mut wet = [0.0; 2];
+        for (channel, out) in wet.iter_mut().enumerate() {
+            let mut sum = 0.0;
+            for comb in &mut self.combs[channel] {
+                sum += comb.process(mono, self.feedback, self.damp);
+            }
+            for allpass in &mut self.allpasses[channel] {
+                sum = allpass.process(sum);
+            }
+            *out = sum;
+        }
+        mix(input, Frame { left: wet[0], right: wet[1] }, self.mix)
+    }
+}
+
+/// Peak compressor on the louder channel, an infinite ratio makes it a limiter.
+pub struct Compressor {
+    threshold: f32,
+    ratio: f32,
+    attack: f32,
+    release: f32,
+    makeup: f32,
+    envelope: f32,
+}
+
+impl Compressor {
+    fn new(sample_rate: f32, threshold: f32, ratio: f32, attack_ms: f32, release_ms: f32, makeup: f32) -> Self {
+        let coefficient = |ms: f32| if ms > 0.0 { (-1.0 / (ms * 0.001 * sample_rate)).exp() } else { 0.0 };
+        Compressor {
+            threshold,
+            ratio: ratio.max(1.0),
+            attack: coefficient(attack_ms),
+            release: coefficient(release_ms),
+            makeup: db_to_gain(makeup),
+            envelope: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: Frame) -> Frame {
+        let peak = input.left.abs().max(input.right.abs());
+        let coefficient = if peak > self.envelope { self.attack } else { self.release };
+        self.envelope = peak + (self.envelope - peak) * coefficient;
+        let level = 20.0 * self.envelope.max(1e-9).log10();
+        let over = level - self.threshold;
+        let gain = if over > 0.0 { db_to_gain(-over * (1.0 - 1.0 / self.ratio)) } else { 1.0 } * self.makeup;
+        Frame { left: input.left * gain, right: input.right * gain }
+    }
+}
+
+/// Lowers bit depth and holds every n-th frame.
+pub struct Bitcrush {
+    levels: f32,
+    hold: usize,
+    counter: usize,
+    held: Frame,
+}
+
+impl Bitcrush {
+    fn process(&mut self, input: Frame) -> Frame {
+        if self.counter == 0 {
+            self.held = Frame {
+                left: (input.left * self.levels).round() / self.levels,
+                right: (input.right * self.levels).round() / self.levels,
+            };
+        }
+        self.counter = (self.counter + 1) % self.hold;
+        self.held
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_take_any_sample_rate() {
+        for id in [LOWPASS, HIGHPASS, BANDPASS] {
+            for sample_rate in [1, 2, 8000] {
+                let mut filter = BuiltinEffect::new(id, sample_rate, &[1000.0]).unwrap();
+                let mut input = vec![Frame::default(); 64];
+                input[0] = Frame { left: 1.0, right: 1.0 };
+                let mut output = vec![Frame::default(); 64];
+                filter.process(&input, &mut output);
+                assert!(output.iter().all(|it| it.left.is_finite() && it.right.is_finite()));
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::flac::FlacWriter;
+use crate::vorbis::VorbisWriter;
+use crate::wav::WavWriter;
+use iaue_plugin::Frame;
+use std::path::Path;
+
+enum Format {
+    Wav(WavWriter),
+    Flac(FlacWriter),
+    Vorbis(VorbisWriter),
+}
+
+/// Writes audio in the format the extension asks for, `.flac` and `.ogg` are encoded and anything else is WAV.
+/// Vorbis ignores the bit depth, FLAC writes 24 bits for float.
+pub struct Encoder {
+    format: Format,
+    clipped: usize,
+}
+
+impl Encoder {
+    pub fn create(path: &Path, sample_rate: u32, bits: u32, comments: &[(String, String)]) -> std::io::Result<Self> {
+        let extension = path.extension().and_then(|it| it.to_str()).unwrap_or_default().to_lowercase();
+        let format = match extension.as_str() {
+            "flac" => Format::Flac(FlacWriter::create(path, sample_rate, bits, comments)?),
+            "ogg" | "oga" => Format::Vorbis(VorbisWriter::create(path, sample_rate, comments)?),
+            _ => Format::Wav(WavWriter::create(path, sample_rate, bits)?),
+        };
+        Ok(Encoder { format, clipped: 0 })
+    }
+
+    pub fn write(&mut self, frames: &[Frame]) -> std::io::Result<()> {
+        self.clipped += frames.iter().flat_map(|it| [it.left, it.right]).filter(|it| it.abs() > 1.0).count();
+        match &mut self.format {
+            Format::Wav(writer) => writer.write(frames),
+            Format::Flac(writer) => writer.write(frames),
+            Format::Vorbis(writer) => writer.write(frames),
+        }
+    }
+
+    /// Samples written so far that were over full scale.
+    pub fn clipped(&self) -> usize {
+        self.clipped
+    }
+
+    pub fn finish(self) -> std::io::Result<()> {
+        match self.format {
+            Format::Wav(writer) => writer.finish(),
+            Format::Flac(writer) => writer.finish(),
+            Format::Vorbis(writer) => writer.finish(),
+        }
+    }
+}
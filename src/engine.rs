@@ -0,0 +1,525 @@
+use crate::effects::BuiltinEffect;
+use crate::instruments::{Builtin, SampleData};
+use crate::plugin::{EffectInstance, Instrument, Note, Plugin};
+use crate::project::{Mixer, Project, Route};
+use crate::routing;
+use iaue_plugin::Frame;
+use std::sync::Arc;
+
+/// Frames rendered per call of `Engine::next_block`.
+pub const BLOCK_SIZE: usize = 1024;
+
+/// One instrument call placed on the timeline of a track.
+#[derive(Debug, Clone)]
+pub struct Voice {
+    /// Index of the note row the voice comes from.
+    pub row: usize,
+    pub start: usize,
+    /// Frames the voice may sound for, layers of a note share the slot of the first one.
+    pub frames: usize,
+    pub freq: f32,
+    pub len: f32,
+    pub vel: f32,
+    pub instrument: u32,
+    pub params: Vec<f32>,
+}
+
+/// Turns the note rows of a track into voices, interpreting the note FX.
+pub fn schedule(project: &Project, track: usize, sample_rate: usize) -> Vec<Voice> {
+    let track = &project.tracks[track];
+    let mut voices = Vec::new();
+    let mut cursor = 0;
+    let (mut fs, mut ls, mut vs) = (track.init.freq, track.init.len, track.init.vel);
+    for (row, note) in track.notes.iter().enumerate() {
+        let mut pushed_args = Vec::new();
+        let (f, l, v) = (note.freq.value(), note.len.value(), note.vel.value());
+        let (old_f, old_l, old_v) = (fs, ls, vs);
+        (fs, ls, vs) = (fs * f, ls * l, v * vs);
+        let (mut new_f, mut new_l, mut new_v) = (fs, ls, vs);
+        let (mut fc, mut lc, mut vc) = (new_f, new_l, new_v);
+        let mut note_repeat = 1;
+        let mut slice_param = 1.0;
+        let mut fx_params_slice = Vec::new();
+        for note_fx in &note.fx {
+            match note_fx.code {
+                0 => {
+                    (fc, lc, vc) = (fc * note_fx.arg(0).unwrap_or(1.0), lc, vc * note_fx.arg(1).unwrap_or(1.0));
+                    pushed_args.push((fc, lc, vc));
+                },
+                1 => {
+                    pushed_args.push((fs * note_fx.arg(0).unwrap_or(1.0), ls, vs * note_fx.arg(1).unwrap_or(1.0)))
+                },
+                2 => { note_repeat *= note_fx.count_arg(0); }
+
+                3 => { fx_params_slice.extend(note_fx.args.iter().map(|it| it.value())); },
+
+                4 => { new_f = note_fx.arg(0).unwrap_or(fs);
+                    fs = new_f;
+                },
+
+                5 => { new_l = note_fx.arg(0).unwrap_or(ls);
+                    ls = new_l;
+                },
+
+                6 => { new_v = note_fx.arg(0).unwrap_or(vs);
+                    vs = new_v;
+                },
+
+                7 => { (new_f, new_l, new_v) = (old_f, old_l, old_v); },
+                8 => {
+                    note_repeat *= note_fx.count_arg(0);
+                    slice_param = if note_repeat == 0 { 1.0 } else { note_repeat as f32 };
+                },
+                9 => {
+                    fs = random_ratio(fs, note_fx.count_arg(0), note_fx.arg(1).unwrap_or(20.0), note_fx.arg(2).unwrap_or(20_000.0));
+                    new_f = fs;
+                },
+                10 => {
+                    ls = random_ratio(ls, note_fx.count_arg(0), note_fx.arg(1).unwrap_or(0.01), note_fx.arg(2).unwrap_or(10.0));
+                    new_l = ls;
+                },
+                11 => {
+                    vs = random_ratio(vs, note_fx.count_arg(0), note_fx.arg(1).unwrap_or(0.1), note_fx.arg(2).unwrap_or(1.0));
+                    new_v = vs;
+                },
+                12 => {
+                    let bound = note_fx.count_arg(0).max(1);
+                    let mut rand_iter = core::iter::repeat_with(|| fastrand::usize(1..=bound));
+                    fs *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
+                    ls *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
+                    vs *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
+                    new_f = fs;
+                    new_l = ls;
+                    new_v = vs;
+                },
+
+                _ => {}
+            }
+        }
+        pushed_args.push((fs, ls, vs));
+        (fs, ls, vs) = (new_f, new_l, new_v);
+        let slot = (pushed_args[0].1 / slice_param * sample_rate as f32) as usize;
+        for repeat in 0..note_repeat {
+            for &(freq, len, vel) in &pushed_args {
+                voices.push(Voice {
+                    row,
+                    start: cursor + repeat * slot,
+                    frames: slot,
+                    freq,
+                    len: len / slice_param,
+                    vel,
+                    instrument: note.instrument,
+                    params: fx_params_slice.clone(),
+                });
+            }
+        }
+        cursor += slot * note_repeat;
+    }
+    voices
+}
+
+/// Multiplies `value` by a random ratio of numbers up to `bound` and folds it into the given range by octaves.
+fn random_ratio(mut value: f32, bound: usize, down_bound: f32, up_bound: f32) -> f32 {
+    let bound = bound.max(1);
+    let mut rand_iter = core::iter::repeat_with(|| fastrand::usize(1..=bound));
+    value *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
+    let mut it = 0;
+    while (value < down_bound) && (it < 8) {
+        value *= 2.0;
+        it += 1;
+    }
+    while (value > up_bound) && (it < 8) {
+        value /= 2.0;
+        it += 1;
+    }
+    value
+}
+
+/// Frames a note row sounds for, repeats included.
+#[derive(Debug, Clone, Copy)]
+pub struct RowSpan {
+    pub row: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+struct ActiveVoice {
+    voice: usize,
+    instrument: Instrument,
+    // Whole output of instruments that can't render a part of a note.
+    rendered: Option<Vec<Frame>>,
+}
+
+struct TrackState {
+    voices: Vec<Voice>,
+    next_voice: usize,
+    active: Vec<ActiveVoice>,
+    effects: Vec<EffectInstance>,
+    buffer: Vec<Frame>,
+    // Copy of the buffer before the effects with the sends and routed tracks summed in, empty unless `keep_dry` was called.
+    dry: Vec<Frame>,
+    // Left and right gain of the mixer strip and whether the track goes into the mix.
+    gains: (f32, f32),
+    audible: bool,
+    // Tracks the effects are keyed from, and the tracks sending or routed to this one with their gain.
+    keys: Vec<usize>,
+    sends: Vec<(usize, f32)>,
+    // False once the track is routed to a bus.
+    to_master: bool,
+}
+
+/// Part of a block `next_block` is working on, the notes of a track counted from 0, then the effects and the mix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Track(usize),
+    Mixing,
+}
+
+/// Renders a project block by block, only the voices sounding in the current block are kept.
+pub struct Engine {
+    sample_rate: usize,
+    tracks: Vec<TrackState>,
+    // Tracks in the order of the routing graph.
+    order: Vec<usize>,
+    scratch: Vec<Frame>,
+    fx_input: Vec<Frame>,
+    fx_output: Vec<Frame>,
+    position: usize,
+    len: usize,
+    looped: Option<(usize, usize)>,
+    samples: Vec<Arc<SampleData>>,
+    // Dropped last, the effect instances above still call into the library.
+    plugin: Option<Plugin>,
+}
+
+impl Engine {
+    /// Without a plugin only the built-in instruments and effects are used, fails if the routes form a cycle.
+    pub fn new(project: &Project, plugin: Option<Plugin>, samples: Vec<Arc<SampleData>>) -> Result<Self, String> {
+        let sample_rate = project.settings.sample_rate as usize;
+        let order = routing::order(project)?;
+        let mut tracks = (0..project.tracks.len())
+            .map(|i| {
+                let effects = project.tracks[i]
+                    .init
+                    .fx
+                    .iter()
+                    .filter_map(|fx| match BuiltinEffect::new(fx.id, sample_rate, &fx.params) {
+                        Some(effect) => Some(EffectInstance::Builtin(effect)),
+                        None => plugin.as_ref()?.effect(fx.id).instance(sample_rate, &fx.params),
+                    })
+                    .collect();
+                TrackState {
+                    voices: schedule(project, i, sample_rate),
+                    next_voice: 0,
+                    active: Vec::new(),
+                    effects,
+                    buffer: vec![Frame::default(); BLOCK_SIZE],
+                    dry: Vec::new(),
+                    gains: (1.0, 1.0),
+                    audible: true,
+                    keys: Vec::new(),
+                    sends: Vec::new(),
+                    to_master: true,
+                }
+            })
+            .collect::<Vec<_>>();
+        let count = tracks.len();
+        for route in project.routes.iter().filter(|it| it.edge().0 < count && it.edge().1 < count) {
+            match *route {
+                Route::Sidechain { track, key } => tracks[track].keys.push(key),
+                Route::Send { from, to, percent } => tracks[to].sends.push((from, percent / 100.0)),
+                Route::Output { from, to } => {
+                    tracks[to].sends.push((from, 1.0));
+                    tracks[from].to_master = false;
+                }
+            }
+        }
+        let len = tracks
+            .iter()
+            .flat_map(|it| it.voices.iter().map(|voice| voice.start + voice.frames))
+            .max()
+            .unwrap_or(0);
+        let mut engine = Engine {
+            sample_rate,
+            tracks,
+            order,
+            scratch: vec![Frame::default(); BLOCK_SIZE],
+            fx_input: vec![Frame::default(); BLOCK_SIZE],
+            fx_output: vec![Frame::default(); BLOCK_SIZE],
+            position: 0,
+            len,
+            looped: None,
+            samples,
+            plugin,
+        };
+        engine.set_mixer(&project.tracks.iter().map(|it| it.mixer).collect::<Vec<_>>());
+        Ok(engine)
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    /// Length of the song in frames.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Frames rendered so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Where every note row of every track sounds.
+    pub fn timeline(&self) -> Vec<Vec<RowSpan>> {
+        self.tracks
+            .iter()
+            .map(|track| {
+                let mut spans: Vec<RowSpan> = Vec::new();
+                for voice in &track.voices {
+                    match spans.last_mut() {
+                        Some(span) if span.row == voice.row => span.end = span.end.max(voice.start + voice.frames),
+                        _ => spans.push(RowSpan { row: voice.row, start: voice.start, end: voice.start + voice.frames }),
+                    }
+                }
+                spans
+            })
+            .collect()
+    }
+
+    /// Continues rendering from `frame`, the effects keep their state.
+    pub fn seek(&mut self, frame: usize) {
+        self.position = frame.min(self.len);
+        for track in &mut self.tracks {
+            track.active.clear();
+            track.next_voice = track
+                .voices
+                .iter()
+                .position(|it| it.start + it.frames > self.position)
+                .unwrap_or(track.voices.len());
+        }
+    }
+
+    /// Repeats the frames from `start` to `end` until the engine is dropped.
+    pub fn set_loop(&mut self, start: usize, end: usize) {
+        self.looped = Some((start, end.min(self.len)));
+    }
+
+    /// Applies the mixer strips of the tracks from the next block on, tracks without one are left as they are.
+    pub fn set_mixer(&mut self, mixers: &[Mixer]) {
+        for ((track, mixer), audible) in self.tracks.iter_mut().zip(mixers).zip(Mixer::audible(mixers)) {
+            track.gains = mixer.gains();
+            track.audible = audible;
+        }
+        // A solo also keeps the tracks that feed it and the ones it feeds audible, unless they are muted.
+        if mixers.iter().any(|it| it.solo) {
+            let mut soloed = (0..self.tracks.len()).map(|it| mixers.get(it).is_some_and(|it| it.solo)).collect::<Vec<_>>();
+            for &track in self.order.iter().rev() {
+                if soloed[track] {
+                    for &(from, _) in &self.tracks[track].sends {
+                        soloed[from] = true;
+                    }
+                }
+            }
+            for &track in &self.order {
+                let fed = self.tracks[track].sends.iter().any(|(from, _)| self.tracks[*from].audible);
+                if (soloed[track] || fed) && !mixers.get(track).is_some_and(|it| it.mute) {
+                    self.tracks[track].audible = true;
+                }
+            }
+        }
+    }
+
+    /// Keeps every track of the next blocks before its effects too, see `track_block`.
+    pub fn keep_dry(&mut self) {
+        for track in &mut self.tracks {
+            track.dry = vec![Frame::default(); BLOCK_SIZE];
+        }
+    }
+
+    /// The last block of `track` after its effects and mixer strip, or before both with `dry`, as many frames as `next_block` returned count.
+    /// Muted tracks are kept too.
+    pub fn track_block(&self, track: usize, dry: bool) -> &[Frame] {
+        let track = &self.tracks[track];
+        if dry { &track.dry } else { &track.buffer }
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// Mixes the next block into `out` and returns the number of frames written, 0 once the song is over.
+    /// `stage` is told when it starts on the notes of every track and when on the effects and the mix.
+    pub fn next_block(&mut self, out: &mut [Frame], stage: &mut dyn FnMut(Stage)) -> usize {
+        let end = match self.looped {
+            Some((start, end)) => {
+                if self.position >= end {
+                    self.seek(start);
+                }
+                end
+            }
+            None => self.len,
+        };
+        let n = out.len().min(BLOCK_SIZE).min(end.saturating_sub(self.position));
+        if n == 0 {
+            return 0;
+        }
+        for track in 0..self.tracks.len() {
+            stage(Stage::Track(track));
+            self.render_voices(track, n);
+        }
+        stage(Stage::Mixing);
+        // Tracks run in the order of the routing graph, so sends and keys are finished before the tracks they go to.
+        for i in 0..self.order.len() {
+            let track = self.order[i];
+            for s in 0..self.tracks[track].sends.len() {
+                let (from, gain) = self.tracks[track].sends[s];
+                if !self.tracks[from].audible {
+                    continue;
+                }
+                for f in 0..n {
+                    let sent = self.tracks[from].buffer[f];
+                    let frame = &mut self.tracks[track].buffer[f];
+                    frame.left += sent.left * gain;
+                    frame.right += sent.right * gain;
+                }
+            }
+            // The dry copy is what goes into the fx, a bus has its inputs by now.
+            let state = &mut self.tracks[track];
+            if !state.dry.is_empty() {
+                state.dry[..n].copy_from_slice(&state.buffer[..n]);
+            }
+            // Taken out so the sidechain slices can borrow every track.
+            let mut effects = std::mem::take(&mut self.tracks[track].effects);
+            for effect in &mut effects {
+                self.fx_input[..n].copy_from_slice(&self.tracks[track].buffer[..n]);
+                // Without declared keys the effects see every track as before, the ones later in the order still without their fx.
+                let sidechains = match self.tracks[track].keys.as_slice() {
+                    [] => self.tracks.iter().map(|it| &it.buffer[..n]).collect::<Vec<_>>(),
+                    keys => keys.iter().map(|it| &self.tracks[*it].buffer[..n]).collect(),
+                };
+                effect.process(&self.fx_input[..n], &mut self.fx_output[..n], &sidechains);
+                self.tracks[track].buffer[..n].copy_from_slice(&self.fx_output[..n]);
+            }
+            self.tracks[track].effects = effects;
+            let state = &mut self.tracks[track];
+            let (left, right) = state.gains;
+            for frame in &mut state.buffer[..n] {
+                frame.left *= left;
+                frame.right *= right;
+            }
+        }
+        for (i, frame) in out[..n].iter_mut().enumerate() {
+            let mut sum = Frame::default();
+            for track in self.tracks.iter().filter(|it| it.audible && it.to_master) {
+                sum.left += track.buffer[i].left;
+                sum.right += track.buffer[i].right;
+            }
+            *frame = Frame { left: sanitize(sum.left), right: sanitize(sum.right) };
+        }
+        self.position += n;
+        n
+    }
+
+    fn render_voices(&mut self, track: usize, n: usize) {
+        let (block_start, block_end) = (self.position, self.position + n);
+        let state = &mut self.tracks[track];
+        state.buffer[..n].fill(Frame::default());
+        while state.next_voice < state.voices.len() && state.voices[state.next_voice].start < block_end {
+            let voice = &state.voices[state.next_voice];
+            let instrument = match Builtin::get(voice.instrument, &voice.params, &self.samples) {
+                Some(builtin) => Instrument::Builtin(builtin),
+                None => self.plugin.as_ref().map(|it| it.instrument(voice.instrument)).unwrap_or(Instrument::Silent),
+            };
+            state.active.push(ActiveVoice { voice: state.next_voice, instrument, rendered: None });
+            state.next_voice += 1;
+        }
+        for active in &mut state.active {
+            let voice = &state.voices[active.voice];
+            let instrument = &active.instrument;
+            if matches!(instrument, Instrument::Silent) {
+                continue;
+            }
+            let from = block_start.saturating_sub(voice.start);
+            let offset = voice.start.saturating_sub(block_start);
+            let count = (n - offset).min(voice.frames.saturating_sub(from));
+            let note = Note {
+                freq: voice.freq,
+                len: voice.len,
+                vel: voice.vel,
+                sample_rate: self.sample_rate,
+                params: &voice.params,
+            };
+            let written = if instrument.streams() {
+                instrument.render_block(&note, from, &mut self.scratch[..count])
+            } else {
+                let rendered = active.rendered.get_or_insert_with(|| instrument.render_all(&note));
+                let part = rendered.get(from..).unwrap_or(&[]);
+                let written = part.len().min(count);
+                self.scratch[..written].copy_from_slice(&part[..written]);
+                written
+            };
+            for (dst, src) in state.buffer[offset..offset + written].iter_mut().zip(&self.scratch[..written]) {
+                dst.left += src.left;
+                dst.right += src.right;
+            }
+        }
+        let voices = &state.voices;
+        state.active.retain(|it| voices[it.voice].start + voices[it.voice].frames > block_end);
+    }
+}
+
+// Keeps inf and NaN from instruments out of the written file.
+pub fn sanitize(sample: f32) -> f32 {
+    if sample == f32::INFINITY { f32::MAX }
+    else if sample == f32::NEG_INFINITY { f32::MIN }
+    else if sample.is_nan() { 0.0 }
+    else { sample }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::Track;
+
+    // Mix of the first block with `mixers`, with the last blocks of the source, the bus and the other track.
+    fn first_block(mixers: &[Mixer]) -> (Vec<Frame>, [Vec<Frame>; 3]) {
+        let mut other = Track::default();
+        other.init.freq = 330.0;
+        let project = Project {
+            tracks: vec![Track::default(), Track::bus("bus".to_string()), other],
+            routes: vec![Route::Send { from: 0, to: 1, percent: 100.0 }],
+            ..Project::default()
+        };
+        let mut engine = Engine::new(&project, None, Vec::new()).unwrap();
+        engine.set_mixer(mixers);
+        let mut out = vec![Frame::default(); BLOCK_SIZE];
+        let n = engine.next_block(&mut out, &mut |_| {});
+        out.truncate(n);
+        let tracks = [0, 1, 2].map(|it| engine.track_block(it, false)[..n].to_vec());
+        (out, tracks)
+    }
+
+    fn silent(frames: &[Frame]) -> bool {
+        frames.iter().all(|it| it.left == 0.0 && it.right == 0.0)
+    }
+
+    #[test]
+    fn solo_keeps_the_bus_it_sends_to() {
+        let solo = Mixer { solo: true, ..Mixer::default() };
+        let (out, [source, bus, _]) = first_block(&[solo, Mixer::default(), Mixer::default()]);
+        assert!(!silent(&bus));
+        for ((out, source), bus) in out.iter().zip(&source).zip(&bus) {
+            assert_eq!(out.left, source.left + bus.left);
+        }
+    }
+
+    #[test]
+    fn muted_source_feeds_nothing() {
+        let mute = Mixer { mute: true, ..Mixer::default() };
+        let (out, [source, bus, other]) = first_block(&[mute, Mixer::default(), Mixer::default()]);
+        assert!(!silent(&source) && !silent(&other));
+        assert!(silent(&bus));
+        assert_eq!(out.iter().map(|it| it.left).collect::<Vec<_>>(), other.iter().map(|it| it.left).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,378 @@
+use crate::wav;
+use iaue_plugin::Frame;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const BLOCK_SIZE: usize = 4096;
+const MAX_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 8;
+// Rice parameters take 4 bits, 15 is the escape code.
+const MAX_RICE_PARAM: u32 = 14;
+
+/// Writes a stereo FLAC file block by block with fixed predictors, 16 bit or otherwise 24 bit with dither like WAV.
+pub struct FlacWriter {
+    out: BufWriter<File>,
+    sample_rate: u32,
+    bits: u32,
+    pending: Vec<Frame>,
+    frames: u64,
+    samples: u64,
+    frame_sizes: (u32, u32),
+}
+
+impl FlacWriter {
+    pub fn create(path: &Path, sample_rate: u32, bits: u32, comments: &[(String, String)]) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let bits = if bits == 16 { 16 } else { 24 };
+        out.write_all(b"fLaC")?;
+        let mut writer = FlacWriter {
+            out,
+            sample_rate,
+            bits,
+            pending: Vec::with_capacity(BLOCK_SIZE),
+            frames: 0,
+            samples: 0,
+            frame_sizes: (u32::MAX, 0),
+        };
+        let stream_info = writer.stream_info();
+        writer.out.write_all(&[0, 0, 0, stream_info.len() as u8])?;
+        writer.out.write_all(&stream_info)?;
+        let comment = vorbis_comment(comments);
+        // The comment block is the last metadata block.
+        writer.out.write_all(&[0x84])?;
+        writer.out.write_all(&(comment.len() as u32).to_be_bytes()[1..])?;
+        writer.out.write_all(&comment)?;
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, frames: &[Frame]) -> std::io::Result<()> {
+        for frame in frames {
+            self.pending.push(*frame);
+            if self.pending.len() == BLOCK_SIZE {
+                self.encode_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            self.encode_block()?;
+        }
+        let stream_info = self.stream_info();
+        self.out.seek(SeekFrom::Start(8))?;
+        self.out.write_all(&stream_info)?;
+        self.out.flush()
+    }
+
+    fn stream_info(&self) -> Vec<u8> {
+        let mut info = BitWriter::default();
+        info.put(BLOCK_SIZE as u64, 16);
+        info.put(BLOCK_SIZE as u64, 16);
+        let (min, max) = if self.frame_sizes.1 == 0 { (0, 0) } else { self.frame_sizes };
+        info.put(min as u64, 24);
+        info.put(max as u64, 24);
+        info.put(self.sample_rate as u64, 20);
+        info.put(1, 3);
+        info.put(self.bits as u64 - 1, 5);
+        info.put(self.samples, 36);
+        // An MD5 of zeros means it wasn't computed.
+        info.put(0, 64);
+        info.put(0, 64);
+        info.bytes
+    }
+
+    fn encode_block(&mut self) -> std::io::Result<()> {
+        let scale = (1i64 << (self.bits - 1)) as f32 - 1.0;
+        let (left, right): (Vec<i64>, Vec<i64>) = self
+            .pending
+            .drain(..)
+            .map(|it| (wav::dither(it.left, scale) as i64, wav::dither(it.right, scale) as i64))
+            .unzip();
+        let n = left.len();
+        let side = left.iter().zip(&right).map(|(l, r)| l - r).collect::<Vec<_>>();
+        let mid = left.iter().zip(&right).map(|(l, r)| (l + r) >> 1).collect::<Vec<_>>();
+        let bits = self.bits;
+        let plans = [plan(&left, bits), plan(&right, bits), plan(&side, bits + 1), plan(&mid, bits)];
+        let cost = |a: usize, b: usize| plans[a].bits + plans[b].bits;
+        // Independent, left and side, side and right, mid and side, in the order the subframes are stored.
+        let assignments = [(0b0001, 0, 1), (0b1000, 0, 2), (0b1001, 2, 1), (0b1010, 3, 2)];
+        let &(assignment, first, second) = assignments.iter().min_by_key(|(_, a, b)| cost(*a, *b)).unwrap_or(&assignments[0]);
+        let channels = [(&left, bits), (&right, bits), (&side, bits + 1), (&mid, bits)];
+
+        let mut frame = BitWriter::default();
+        frame.put(0x3FFE, 14);
+        frame.put(0, 2);
+        // Block size in 16 bits at the end of the header, sample rate from the stream info.
+        frame.put(0b0111, 4);
+        frame.put(0, 4);
+        frame.put(assignment, 4);
+        frame.put(if bits == 16 { 0b100 } else { 0b110 }, 3);
+        frame.put(0, 1);
+        frame.bytes.extend(utf8_number(self.frames));
+        frame.put(n as u64 - 1, 16);
+        let crc = crc8(&frame.bytes);
+        frame.put(crc as u64, 8);
+        for channel in [first, second] {
+            let (samples, bits) = channels[channel];
+            write_subframe(&mut frame, samples, bits, &plans[channel]);
+        }
+        frame.align();
+        let crc = crc16(&frame.bytes);
+        frame.put(crc as u64, 16);
+
+        let size = frame.bytes.len() as u32;
+        self.frame_sizes = (self.frame_sizes.0.min(size), self.frame_sizes.1.max(size));
+        self.frames += 1;
+        self.samples += n as u64;
+        self.out.write_all(&frame.bytes)
+    }
+}
+
+/// How a subframe is stored and its size in bits.
+struct Plan {
+    kind: Kind,
+    bits: u64,
+}
+
+enum Kind {
+    Constant,
+    Verbatim,
+    Fixed { order: usize, partition_order: u32, params: Vec<u32> },
+}
+
+// Picks the cheapest of a constant, the raw samples and the fixed predictors with rice coded residuals.
+fn plan(samples: &[i64], bits: u32) -> Plan {
+    if samples.iter().all(|it| *it == samples[0]) {
+        return Plan { kind: Kind::Constant, bits: 8 + bits as u64 };
+    }
+    let mut best = Plan { kind: Kind::Verbatim, bits: 8 + (samples.len() * bits as usize) as u64 };
+    for order in 0..=MAX_ORDER.min(samples.len() - 1) {
+        let residual = residual(samples, order);
+        for partition_order in 0..=MAX_PARTITION_ORDER {
+            let partitions = 1 << partition_order;
+            if !samples.len().is_multiple_of(partitions) || samples.len() / partitions <= order {
+                break;
+            }
+            let mut params = Vec::new();
+            let mut cost = 8 + (order * bits as usize) as u64 + 6;
+            for part in partitions_of(&residual, samples.len(), order, partition_order) {
+                let (param, part_cost) = rice_param(part);
+                params.push(param);
+                cost += 4 + part_cost;
+            }
+            if cost < best.bits {
+                best = Plan { kind: Kind::Fixed { order, partition_order, params }, bits: cost };
+            }
+        }
+    }
+    best
+}
+
+fn residual(samples: &[i64], order: usize) -> Vec<i64> {
+    const COEFFICIENTS: [&[i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+    (order..samples.len())
+        .map(|i| samples[i] - COEFFICIENTS[order].iter().enumerate().map(|(j, c)| c * samples[i - 1 - j]).sum::<i64>())
+        .collect()
+}
+
+// The first partition is shorter by the warm up samples.
+fn partitions_of(residual: &[i64], n: usize, order: usize, partition_order: u32) -> impl Iterator<Item = &[i64]> {
+    let len = n >> partition_order;
+    let first = len - order;
+    std::iter::once(&residual[..first]).chain(residual[first..].chunks(len))
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+// Tries the parameters around the one the mean suggests, returns the best and its size in bits.
+fn rice_param(part: &[i64]) -> (u32, u64) {
+    let sum = part.iter().map(|it| zigzag(*it)).sum::<u64>();
+    let mean = sum / part.len().max(1) as u64;
+    let guess = (64 - mean.leading_zeros()).min(MAX_RICE_PARAM);
+    (guess.saturating_sub(1)..=(guess + 1).min(MAX_RICE_PARAM))
+        .map(|param| (param, part.iter().map(|it| (zigzag(*it) >> param) + 1 + param as u64).sum::<u64>()))
+        .min_by_key(|it| it.1)
+        .unwrap_or((0, 0))
+}
+
+fn write_subframe(out: &mut BitWriter, samples: &[i64], bits: u32, plan: &Plan) {
+    match &plan.kind {
+        Kind::Constant => {
+            out.put(0, 8);
+            out.put_signed(samples[0], bits);
+        }
+        Kind::Verbatim => {
+            out.put(0b0000_0010, 8);
+            for sample in samples {
+                out.put_signed(*sample, bits);
+            }
+        }
+        Kind::Fixed { order, partition_order, params } => {
+            out.put(0b0001_0000 | (*order as u64) << 1, 8);
+            for sample in &samples[..*order] {
+                out.put_signed(*sample, bits);
+            }
+            out.put(0, 2);
+            out.put(*partition_order as u64, 4);
+            let residual = residual(samples, *order);
+            for (part, param) in partitions_of(&residual, samples.len(), *order, *partition_order).zip(params) {
+                out.put(*param as u64, 4);
+                for value in part {
+                    let value = zigzag(*value);
+                    out.put_zeros(value >> param);
+                    out.put(1, 1);
+                    out.put(value, *param);
+                }
+            }
+        }
+    }
+}
+
+/// Packs bits most significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.put(value >> 32, bits - 32);
+            self.put(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+        self.acc = self.acc << bits | (value & ((1u64 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    fn put_signed(&mut self, value: i64, bits: u32) {
+        self.put(value as u64, bits);
+    }
+
+    fn put_zeros(&mut self, mut count: u64) {
+        while count > 0 {
+            let bits = count.min(32) as u32;
+            self.put(0, bits);
+            count -= bits as u64;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.put(0, 8 - self.bits);
+        }
+    }
+}
+
+// Frame numbers are coded like UTF-8 characters, up to 36 bits.
+fn utf8_number(value: u64) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+    let mut len = 2;
+    while len < 7 && value >= 1 << (5 * len + 1) {
+        len += 1;
+    }
+    let mut bytes = (1..len).rev().map(|i| 0x80 | (value >> (6 * (i - 1))) as u8 & 0x3F).collect::<Vec<_>>();
+    bytes.insert(0, (0xFF00u16 >> len) as u8 | (value >> (6 * (len - 1))) as u8);
+    bytes
+}
+
+/// The comment block shared by FLAC and Vorbis, `KEY=value` pairs after the vendor string, lengths are little endian.
+pub fn vorbis_comment(comments: &[(String, String)]) -> Vec<u8> {
+    let vendor = concat!("iaue ", env!("CARGO_PKG_VERSION"));
+    let mut data = Vec::new();
+    data.extend((vendor.len() as u32).to_le_bytes());
+    data.extend(vendor.as_bytes());
+    data.extend((comments.len() as u32).to_le_bytes());
+    for (key, value) in comments {
+        let comment = format!("{}={}", key, value);
+        data.extend((comment.len() as u32).to_le_bytes());
+        data.extend(comment.as_bytes());
+    }
+    data
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { crc << 1 ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, byte| {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two sines that don't line up with the blocks, a little under full scale.
+    fn signal(len: usize) -> Vec<Frame> {
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / 44100.0;
+                Frame { left: 0.8 * (t * 440.0 * std::f32::consts::TAU).sin(), right: 0.5 * (t * 1234.5 * std::f32::consts::TAU).cos() }
+            })
+            .collect()
+    }
+
+    // Encodes `len` frames in uneven writes, decodes them with claxon and checks they are the dithered samples.
+    fn round_trip(len: usize, bits: u32) {
+        let path = std::env::temp_dir().join(format!("iaue-flac-{}-{}-{}.flac", std::process::id(), len, bits));
+        let frames = signal(len);
+        let scale = (1i64 << (bits - 1)) as f32 - 1.0;
+        fastrand::seed(len as u64);
+        let expected = frames.iter().flat_map(|it| [wav::dither(it.left, scale), wav::dither(it.right, scale)]).collect::<Vec<_>>();
+        fastrand::seed(len as u64);
+        let mut writer = FlacWriter::create(&path, 44100, bits, &[("TITLE".to_string(), "test".to_string())]).unwrap();
+        for part in frames.chunks(1000) {
+            writer.write(part).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = claxon::FlacReader::open(&path).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!((info.sample_rate, info.channels, info.bits_per_sample), (44100, 2, bits));
+        // A total of 0 means unknown in the stream info.
+        assert_eq!(info.samples.unwrap_or(0), len as u64);
+        assert_eq!(reader.get_tag("TITLE").collect::<Vec<_>>(), ["test"]);
+        let decoded = reader.samples().collect::<Result<Vec<_>, _>>().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn round_trips_16_bit() {
+        for len in [0, 1, BLOCK_SIZE, BLOCK_SIZE + 1] {
+            round_trip(len, 16);
+        }
+    }
+
+    #[test]
+    fn round_trips_24_bit() {
+        for len in [0, 1, BLOCK_SIZE, BLOCK_SIZE + 1] {
+            round_trip(len, 24);
+        }
+    }
+}
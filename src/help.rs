@@ -1,2 +1,4 @@
-pub static TEXT: [&str; 2] = [include_str!("../docs/keys.txt"),
-include_str!("../docs/note_fx.txt")];
+pub static TEXT: [&str; 4] = [include_str!("../docs/keys.txt"),
+include_str!("../docs/note_fx.txt"),
+include_str!("../docs/instruments.txt"),
+include_str!("../docs/track_fx.txt")];
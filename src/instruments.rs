@@ -0,0 +1,148 @@
+use crate::plugin::Note;
+use crate::project::Sample;
+use crate::wav;
+use iaue_plugin::Frame;
+use std::f64::consts::TAU;
+use std::sync::Arc;
+
+// Ids from 1000 on are reserved for instruments built into iaue, user libraries use the ones below.
+pub const SINE: u32 = 1000;
+pub const SAW: u32 = 1001;
+pub const SQUARE: u32 = 1002;
+pub const TRIANGLE: u32 = 1003;
+pub const NOISE: u32 = 1004;
+pub const FM: u32 = 1005;
+pub const SAMPLER: u32 = 1006;
+// Sample `n` of the project is also instrument `SAMPLES + n`, playing it at its own root frequency.
+pub const SAMPLES: u32 = 2000;
+
+/// Audio of a project sample resampled on playback.
+pub struct SampleData {
+    pub sample_rate: u32,
+    pub frames: Vec<Frame>,
+    pub root: f32,
+}
+
+impl SampleData {
+    /// Reads the WAV file of `sample` and keeps the part between its trim points.
+    pub fn load(sample: &Sample) -> Result<Self, String> {
+        let (sample_rate, mut frames) = wav::read(std::path::Path::new(&sample.path))?;
+        let frame = |seconds: f32| ((seconds.max(0.0) * sample_rate as f32) as usize).min(frames.len());
+        let start = frame(sample.start);
+        let end = if sample.end > sample.start { frame(sample.end) } else { frames.len() };
+        frames.truncate(end);
+        frames.drain(..start);
+        Ok(SampleData { sample_rate, frames, root: sample.root })
+    }
+}
+
+#[derive(Clone)]
+pub enum Builtin {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    Noise,
+    Fm,
+    Sampler(Arc<SampleData>, f32),
+}
+
+impl Builtin {
+    /// Returns the built-in instrument with `id`, the sample player picks its sample from `params[2]`
+    /// and its root frequency from `params[3]`, falling back to the root stored with the sample.
+    pub fn get(id: u32, params: &[f32], samples: &[Arc<SampleData>]) -> Option<Self> {
+        Some(match id {
+            SINE => Builtin::Sine,
+            SAW => Builtin::Saw,
+            SQUARE => Builtin::Square,
+            TRIANGLE => Builtin::Triangle,
+            NOISE => Builtin::Noise,
+            FM => Builtin::Fm,
+            SAMPLER => {
+                let index = param(params, 2, 0.0) as usize;
+                // A missing sample stays silent like a missing library instrument.
+                let sample = samples.get(index)?;
+                Builtin::Sampler(sample.clone(), param(params, 3, sample.root))
+            }
+            id if id >= SAMPLES => {
+                let sample = samples.get((id - SAMPLES) as usize)?;
+                Builtin::Sampler(sample.clone(), sample.root)
+            }
+            _ => return None,
+        })
+    }
+
+    /// Renders the note from frame `start` on. Params are attack and release in seconds, then per instrument:
+    /// square pulse width, FM modulator ratio and index, sample index and root frequency for the sampler.
+    pub fn render(&self, note: &Note, start: usize, out: &mut [Frame]) -> usize {
+        let sample_rate = note.sample_rate as f64;
+        let freq = note.freq as f64;
+        let attack = param(note.params, 0, 0.005);
+        let release = param(note.params, 1, 0.05);
+        let frames = ((note.len * note.sample_rate as f32) as usize).saturating_sub(start).min(out.len());
+        for (i, frame) in out[..frames].iter_mut().enumerate() {
+            let n = (start + i) as f64;
+            let phase = (freq * n / sample_rate).fract();
+            let dt = freq / sample_rate;
+            let value = match self {
+                Builtin::Sine => (phase * TAU).sin(),
+                Builtin::Saw => 2.0 * phase - 1.0 - poly_blep(phase, dt),
+                Builtin::Square => {
+                    let width = param(note.params, 2, 0.5).clamp(0.01, 0.99) as f64;
+                    let naive = if phase < width { 1.0 } else { -1.0 };
+                    naive + poly_blep(phase, dt) - poly_blep((phase + 1.0 - width).fract(), dt)
+                }
+                Builtin::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+                Builtin::Noise => fastrand::f64() * 2.0 - 1.0,
+                Builtin::Fm => {
+                    let ratio = param(note.params, 2, 2.0) as f64;
+                    let index = param(note.params, 3, 1.0) as f64;
+                    let modulator = (freq * ratio * n / sample_rate).fract();
+                    (phase * TAU + index * (modulator * TAU).sin()).sin()
+                }
+                Builtin::Sampler(sample, root) => {
+                    let step = freq / *root as f64 * sample.sample_rate as f64 / sample_rate;
+                    let pos = n * step;
+                    let index = pos as usize;
+                    let Some(a) = sample.frames.get(index) else { return i };
+                    let b = sample.frames.get(index + 1).unwrap_or(a);
+                    let t = pos.fract() as f32;
+                    let gain = envelope(start + i, note, attack, release) * note.vel;
+                    *frame = Frame {
+                        left: (a.left + (b.left - a.left) * t) * gain,
+                        right: (a.right + (b.right - a.right) * t) * gain,
+                    };
+                    continue;
+                }
+            } as f32;
+            let value = value * envelope(start + i, note, attack, release) * note.vel;
+            *frame = Frame { left: value, right: value };
+        }
+        frames
+    }
+}
+
+fn param(params: &[f32], i: usize, default: f32) -> f32 {
+    params.get(i).copied().unwrap_or(default)
+}
+
+// Linear fade in over `attack` and out over the last `release` seconds of the note, keeps notes from clicking.
+fn envelope(frame: usize, note: &Note, attack: f32, release: f32) -> f32 {
+    let t = frame as f32 / note.sample_rate as f32;
+    let fade_in = if attack > 0.0 { t / attack } else { 1.0 };
+    let fade_out = if release > 0.0 { (note.len - t) / release } else { 1.0 };
+    fade_in.min(fade_out).clamp(0.0, 1.0)
+}
+
+// Smooths the jump of saw and square waves to reduce aliasing.
+fn poly_blep(phase: f64, dt: f64) -> f64 {
+    if phase < dt {
+        let t = phase / dt;
+        t + t - t * t - 1.0
+    } else if phase > 1.0 - dt {
+        let t = (phase - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,100 @@
+use crate::project::Normalize;
+use iaue_plugin::Frame;
+use std::f64::consts::PI;
+
+// Blocks of 400 ms that start every 100 ms, below -70 LUFS a block is left out.
+const BLOCK_SECONDS: f64 = 0.4;
+const STEP_SECONDS: f64 = 0.1;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+// The K-weighting of BS.1770, a high shelf for the head and a high pass, recomputed for any sample rate.
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let k = (PI * 1681.974450955533 / sample_rate).tan();
+    let q = 0.7071752369554196;
+    let vh = 10f64.powf(3.999843853973347 / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+    let k = (PI * 38.13547087602444 / sample_rate).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+    [shelf, high_pass]
+}
+
+/// Highest absolute sample of both channels.
+pub fn peak(frames: &[Frame]) -> f32 {
+    frames.iter().fold(0.0, |peak, it| peak.max(it.left.abs()).max(it.right.abs()))
+}
+
+/// Integrated loudness in LUFS as ITU-R BS.1770 measures it, `None` when everything is below the gate.
+pub fn integrated(frames: &[Frame], sample_rate: usize) -> Option<f32> {
+    let mut filters = [k_weighting(sample_rate as f64), k_weighting(sample_rate as f64)];
+    // Squared sum of both weighted channels for every step, blocks are made of consecutive steps.
+    let step = ((STEP_SECONDS * sample_rate as f64) as usize).max(1);
+    let steps_per_block = (BLOCK_SECONDS / STEP_SECONDS).round() as usize;
+    let mut steps = Vec::new();
+    for chunk in frames.chunks(step) {
+        let mut sum = 0.0;
+        for frame in chunk {
+            for (filter, sample) in filters.iter_mut().zip([frame.left, frame.right]) {
+                let weighted = filter.iter_mut().fold(sample as f64, |x, it| it.process(x));
+                sum += weighted * weighted;
+            }
+        }
+        steps.push(sum);
+    }
+    // A song shorter than a block is measured as one block.
+    let block_len = (step * steps_per_block).min(frames.len()).max(1) as f64;
+    let blocks = steps
+        .windows(steps_per_block.min(steps.len()).max(1))
+        .map(|it| it.iter().sum::<f64>() / block_len)
+        .collect::<Vec<_>>();
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let mean = |gate: f64| {
+        let passed = blocks.iter().filter(|it| loudness(**it) > gate).collect::<Vec<_>>();
+        (!passed.is_empty()).then(|| passed.iter().copied().sum::<f64>() / passed.len() as f64)
+    };
+    let relative = loudness(mean(ABSOLUTE_GATE)?) + RELATIVE_GATE;
+    mean(relative.max(ABSOLUTE_GATE)).map(|it| loudness(it) as f32)
+}
+
+/// Gain that brings the frames to the target of `normalize`, 1 when off or silent.
+pub fn normalize_gain(frames: &[Frame], sample_rate: usize, normalize: Normalize) -> f32 {
+    let db = match normalize {
+        Normalize::Off => return 1.0,
+        Normalize::Peak(target) => match peak(frames) {
+            peak if peak > 0.0 => target - 20.0 * peak.log10(),
+            _ => return 1.0,
+        },
+        Normalize::Lufs(target) => match integrated(frames, sample_rate) {
+            Some(loudness) => target - loudness,
+            None => return 1.0,
+        },
+    };
+    10f32.powf(db / 20.0)
+}
@@ -1,17 +1,37 @@
+mod dawproject;
+mod effects;
+mod encoder;
+mod engine;
+mod flac;
 mod help;
 mod init_config;
+mod instruments;
+mod loudness;
+mod midi;
+mod pd;
+mod plugin;
+mod project;
+mod routing;
+mod sunvox;
+mod vorbis;
+mod wav;
+mod zip;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use iaue_plugin::Frame;
 use crossterm::{
     event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
 use ratatui::layout::Direction;
+use project::{Normalize, NoteRow, Project, Settings, Track, INIT_ROW, MIXER_ROW, NOTE_ROW};
 use ratatui::{backend::CrosstermBackend, prelude::*, style::Stylize, widgets::*, Terminal};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::{stdout, Result};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::{
     fs,
     io::{Read, Write},
@@ -52,29 +72,444 @@ pub struct App<'a> {
     theme: HashMap<String, style::Color>,
     x_bound: u16,
     y_bound: u16,
-    cols: Vec<Vec<Vec<Span<'a>>>>,
-    yank_buf: Vec<Vec<Vec<Span<'a>>>>,
+    //First track column and grid row on screen
+    scroll_x: u16,
+    scroll_y: u16,
+    pending_key: Option<char>,
+    project: Project,
+    lines: Vec<Span<'a>>,
+    insert_text: Option<((u16, u16, u16), String)>,
+    yank_buf: Vec<Vec<NoteRow>>,
+    history: History,
+    dirty: bool,
+    //Open projects, the one at `current_tab` is stale while its state lives in the fields above
+    tabs: Vec<Tab>,
+    current_tab: usize,
     //constrains: Vec<Constraint>,
+    render_job: Option<RenderJob>,
+    render_status: String,
     help_page: usize,
     is_help: bool,
     should_leave: bool,
 }
 
 impl App<'_> {
+    fn new(file_name: &str, theme: HashMap<String, style::Color>) -> Self {
+        App {
+            normal_cursor: NormalCursor{ x: 1, y: INIT_ROW as u16},
+            visual_cursor: VisualCursor{ x: 1, y: INIT_ROW as u16},
+            insert_cursor: InsertCursor::default(),
+            current_mode: Mode::Normal,
+            audio_params: OutputDeviceParameters {
+                channels_count: 2,
+                sample_rate: project::Settings::default().sample_rate as usize,
+                channel_sample_count: 4410,
+            },
+            command_buf: String::new(),
+            //file_path: std::env::current_dir().unwrap().to_str().unwrap_or("/").to_string(),
+            file_name: file_name.to_string(),
+            theme,
+            x_bound: 0,
+            y_bound: 0,
+            scroll_x: 1,
+            scroll_y: 0,
+            pending_key: None,
+            current_times: String::new(),
+            project: Tab::new().project,
+            lines: Vec::new(),
+            insert_text: None,
+            yank_buf: Vec::new(),
+            history: History::default(),
+            dirty: false,
+            tabs: vec![Tab::default()],
+            current_tab: 0,
+            //constrains: vec![Constraint::Max(3); 6],
+            render_job: None,
+            render_status: String::new(),
+            help_page: 0,
+            is_help: false,
+            should_leave: false,
+        }
+    }
     fn count_lines(&mut self) {
-        let max_y = self.cols[1..].iter().map(|it| it.len()).max().unwrap_or(0);
+        let max_y = self.project.tracks.iter().map(|it| it.row_count()).max().unwrap_or(0);
         let mut cols = (0..max_y as isize)
             .map(|it| (it - self.normal_cursor.y as isize).abs())
-            .map(|it| vec![Span::from(it.to_string()).style(self.theme["fg_dark"])])
+            .map(|it| Span::from(it.to_string()).style(self.theme["fg_dark"]))
             .collect::<Vec<_>>();
-        cols[self.normal_cursor.y as usize][0] =
+        cols[self.normal_cursor.y as usize] =
             Span::from(self.normal_cursor.y.to_string()).style(self.theme["orange"]);
-        self.cols[0] = cols;
+        self.lines = cols;
     }
     fn count_bound(&self) -> usize {
-        let bound = self.cols[self.normal_cursor.x as usize][self.normal_cursor.y as usize].iter().map(|it| it.content.len() + 1).sum::<usize>();
+        let bound = self.cell_fields(self.normal_cursor.x, self.normal_cursor.y).iter().map(|it| it.len() + 1).sum::<usize>();
         if bound < 14 { 14 } else { bound }
     }
+    fn col_width(&self, x: u16) -> u16 {
+        match x {
+            0 => 4,
+            x if x == self.normal_cursor.x => self.count_bound() as u16,
+            _ => 14,
+        }
+    }
+    ///Grid rows that fit between the track names and the status lines
+    fn view_height(&self) -> u16 {
+        self.y_bound.saturating_sub(4).max(1)
+    }
+    ///Track columns that fit on screen from `scroll_x` on, at least one
+    fn visible_cols(&self) -> std::ops::Range<u16> {
+        let mut width = self.x_bound.saturating_sub(2 + self.col_width(0));
+        let mut end = self.scroll_x;
+        while (end as usize) < self.col_count() && self.col_width(end) <= width {
+            width -= self.col_width(end);
+            end += 1;
+        }
+        self.scroll_x..end.max(self.scroll_x + 1).min(self.col_count() as u16)
+    }
+    ///Moves the viewport so the normal cursor stays visible
+    fn scroll_to_cursor(&mut self) {
+        let height = self.view_height();
+        if self.normal_cursor.y < self.scroll_y {
+            self.scroll_y = self.normal_cursor.y;
+        } else if self.normal_cursor.y >= self.scroll_y + height {
+            self.scroll_y = self.normal_cursor.y + 1 - height;
+        }
+        self.scroll_y = self.scroll_y.min((self.lines.len() as u16).saturating_sub(height));
+        self.scroll_x = self.scroll_x.clamp(1, (self.col_count() as u16 - 1).max(1));
+        if self.normal_cursor.x < self.scroll_x {
+            self.scroll_x = self.normal_cursor.x;
+        }
+        while self.normal_cursor.x >= self.visible_cols().end && self.scroll_x < self.normal_cursor.x {
+            self.scroll_x += 1;
+        }
+    }
+    ///Moves the cursor and the viewport by half a screen, like Ctrl+D and Ctrl+U in vim
+    fn scroll_half_page(&mut self, down: bool) {
+        let step = (self.view_height() / 2).max(1);
+        let last_row = self.row_count(self.normal_cursor.x) as u16 - 1;
+        if down {
+            self.normal_cursor.y = (self.normal_cursor.y + step).min(last_row);
+            self.scroll_y += step;
+        } else {
+            self.normal_cursor.y = self.normal_cursor.y.saturating_sub(step);
+            self.scroll_y = self.scroll_y.saturating_sub(step);
+        }
+        self.count_lines();
+    }
+    fn col_count(&self) -> usize {
+        self.project.tracks.len() + 1
+    }
+    fn row_count(&self, x: u16) -> usize {
+        match x {
+            0 => self.lines.len(),
+            x => self.project.tracks[x as usize - 1].row_count(),
+        }
+    }
+    fn field_count(&self, x: u16, y: u16) -> usize {
+        match x {
+            0 => 1,
+            x => self.project.tracks[x as usize - 1].field_count(y as usize),
+        }
+    }
+    ///Moves the insert cursor `count` fields left, wrapping to the cells on the left.
+    ///A cell without fields, like the init row of a bus without fx, counts as one field.
+    fn insert_left(&mut self, count: u16) {
+        let insert_bound = self.field_count(self.normal_cursor.x, self.normal_cursor.y).max(1) as isize;
+        let new_cursor_insert = self.insert_cursor.x as isize - count as isize;
+        let new_cursor_normal = self.normal_cursor.x as isize
+            - (((new_cursor_insert - insert_bound + 1) / insert_bound).abs());
+        if new_cursor_normal > 0 && self.normal_cursor.y >= self.row_count(new_cursor_normal as u16) as u16 {
+        }
+        else if new_cursor_normal > 0 {
+            self.insert_cursor.x = new_cursor_insert.rem_euclid(insert_bound) as u16;
+            self.normal_cursor.x = new_cursor_normal as u16;
+        } else {
+            self.normal_cursor.x = 1;
+            self.insert_cursor.x = 0;
+        }
+        self.clamp_insert_cursor();
+    }
+    ///Moves the insert cursor `count` fields right, wrapping to the cells on the right.
+    fn insert_right(&mut self, count: u16) {
+        let insert_bound = self.field_count(self.normal_cursor.x, self.normal_cursor.y).max(1) as u16;
+        let new_cursor_insert = self.insert_cursor.x + count;
+        let new_cursor_normal = self
+            .normal_cursor
+            .x
+            .saturating_add(new_cursor_insert / insert_bound);
+        if new_cursor_normal < self.col_count() as u16 && self.normal_cursor.y >= self.row_count(new_cursor_normal) as u16 {
+        }
+        else if new_cursor_normal < self.col_count() as u16 {
+            self.insert_cursor.x = new_cursor_insert % insert_bound;
+            self.normal_cursor.x = new_cursor_normal;
+        } else {
+            self.insert_cursor.x = insert_bound - 1;
+            self.normal_cursor.x = self.col_count() as u16 - 1;
+        }
+        self.clamp_insert_cursor();
+    }
+    ///Keeps the insert cursor on a field of the cell it moved to
+    fn clamp_insert_cursor(&mut self) {
+        let last = self.field_count(self.normal_cursor.x, self.normal_cursor.y).max(1) - 1;
+        self.insert_cursor.x = self.insert_cursor.x.min(last as u16);
+    }
+    fn track(&self) -> &Track {
+        &self.project.tracks[self.normal_cursor.x as usize - 1]
+    }
+    fn track_mut(&mut self) -> &mut Track {
+        &mut self.project.tracks[self.normal_cursor.x as usize - 1]
+    }
+    fn cell_fields(&self, x: u16, y: u16) -> Vec<String> {
+        let mut fields = match x {
+            0 => vec![self.lines[y as usize].content.to_string()],
+            x => self.project.tracks[x as usize - 1].fields(y as usize),
+        };
+        if let (Mode::Insert, Some(((ix, iy, ii), text))) = (&self.current_mode, &self.insert_text) {
+            if (*ix, *iy) == (x, y) && (*ii as usize) < fields.len() {
+                fields[*ii as usize] = text.clone();
+            }
+        }
+        fields
+    }
+    ///Grid row of track column `x` that is playing right now
+    fn playhead_row(&self, x: u16) -> Option<usize> {
+        let job = self.render_job.as_ref().filter(|it| it.tab == self.current_tab)?;
+        let spans = job.timeline.as_ref()?.get((x as usize).checked_sub(1)?)?;
+        let position = job.transport.position.load(Ordering::Relaxed);
+        spans
+            .iter()
+            .find(|it| it.start <= position && position < it.end)
+            .map(|it| it.row + NOTE_ROW)
+    }
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            project: self.project.clone(),
+            cursor: (self.normal_cursor.x, self.normal_cursor.y, self.insert_cursor.x),
+        }
+    }
+    ///Saves the state before an edit, edits of one insert mode session are undone together
+    fn record(&mut self) {
+        self.dirty = true;
+        if let Mode::Insert = self.current_mode {
+            if self.history.in_insert {
+                return;
+            }
+            self.history.in_insert = true;
+        } else {
+            self.history.in_insert = false;
+        }
+        let snapshot = self.snapshot();
+        self.history.undo.push(snapshot);
+        if self.history.undo.len() > HISTORY_LIMIT {
+            self.history.undo.remove(0);
+        }
+        self.history.redo.clear();
+    }
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.project = snapshot.project;
+        let (x, y, insert_x) = snapshot.cursor;
+        self.normal_cursor.x = x.clamp(1, self.project.tracks.len() as u16);
+        self.normal_cursor.y = y.min(self.row_count(self.normal_cursor.x) as u16 - 1);
+        self.insert_cursor.x = insert_x.min(self.field_count(self.normal_cursor.x, self.normal_cursor.y).saturating_sub(1) as u16);
+        self.insert_text = None;
+        self.history.in_insert = false;
+        self.current_mode = Mode::Normal;
+        self.dirty = true;
+        self.count_lines();
+    }
+    fn undo(&mut self) {
+        match self.history.undo.pop() {
+            Some(snapshot) => {
+                let current = self.snapshot();
+                self.history.redo.push(current);
+                self.restore(snapshot);
+            }
+            None => self.command_buf = "Already at oldest change.".to_string(),
+        }
+    }
+    fn redo(&mut self) {
+        match self.history.redo.pop() {
+            Some(snapshot) => {
+                let current = self.snapshot();
+                self.history.undo.push(current);
+                self.restore(snapshot);
+            }
+            None => self.command_buf = "Already at newest change.".to_string(),
+        }
+    }
+    fn take_tab(&mut self) -> Tab {
+        Tab {
+            file_name: std::mem::take(&mut self.file_name),
+            dir: std::env::current_dir().unwrap_or_default(),
+            project: std::mem::take(&mut self.project),
+            normal_cursor: std::mem::take(&mut self.normal_cursor),
+            visual_cursor: std::mem::take(&mut self.visual_cursor),
+            insert_cursor: std::mem::take(&mut self.insert_cursor),
+            scroll: (self.scroll_x, self.scroll_y),
+            history: std::mem::take(&mut self.history),
+            dirty: self.dirty,
+        }
+    }
+    fn put_tab(&mut self, tab: Tab) {
+        self.file_name = tab.file_name;
+        let _ = std::env::set_current_dir(&tab.dir);
+        self.project = tab.project;
+        self.normal_cursor = tab.normal_cursor;
+        self.visual_cursor = tab.visual_cursor;
+        self.insert_cursor = tab.insert_cursor;
+        (self.scroll_x, self.scroll_y) = tab.scroll;
+        self.history = tab.history;
+        self.dirty = tab.dirty;
+        self.audio_params.sample_rate = self.project.settings.sample_rate as usize;
+        self.insert_text = None;
+        self.current_mode = Mode::Normal;
+        self.count_lines();
+    }
+    ///Tab names on the top border, a + marks unsaved changes
+    fn tab_bar(&self) -> Line<'_> {
+        Line::from(
+            self.tabs
+                .iter()
+                .enumerate()
+                .map(|(i, tab)| {
+                    let (name, dirty) = if i == self.current_tab { (&self.file_name, self.dirty) } else { (&tab.file_name, tab.dirty) };
+                    let title = Span::from(format!(" {} {}{} ", i + 1, name, if dirty { "+" } else { "" }));
+                    if i == self.current_tab { title.add_modifier(Modifier::REVERSED) } else { title }
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+    fn switch_tab(&mut self, index: usize) {
+        if index == self.current_tab || index >= self.tabs.len() {
+            return;
+        }
+        self.tabs[self.current_tab] = self.take_tab();
+        let tab = std::mem::take(&mut self.tabs[index]);
+        self.current_tab = index;
+        self.put_tab(tab);
+    }
+    ///Next tab, or tab number `count` like `{count}gt` in vim
+    fn next_tab(&mut self, count: Option<usize>) {
+        let index = match count {
+            Some(count) => count.saturating_sub(1),
+            None => (self.current_tab + 1) % self.tabs.len(),
+        };
+        self.switch_tab(index);
+    }
+    fn prev_tab(&mut self) {
+        self.switch_tab((self.current_tab + self.tabs.len() - 1) % self.tabs.len());
+    }
+    fn new_tab(&mut self) {
+        self.tabs[self.current_tab] = self.take_tab();
+        self.current_tab += 1;
+        let file_name = format!("project{}.tr", self.tabs.len() + 1);
+        self.tabs.insert(self.current_tab, Tab::default());
+        self.put_tab(Tab { file_name, ..Tab::new() });
+    }
+    fn close_tab(&mut self) {
+        if self.tabs.len() == 1 {
+            self.should_leave = true;
+            return;
+        }
+        if let Some(job) = &mut self.render_job {
+            //Stops playback of the closed project
+            if job.tab == self.current_tab {
+                job.cancel.store(true, Ordering::Relaxed);
+                job.tab = usize::MAX;
+            } else if job.tab > self.current_tab {
+                job.tab -= 1;
+            }
+        }
+        self.tabs.remove(self.current_tab);
+        self.current_tab = self.current_tab.min(self.tabs.len() - 1);
+        let tab = std::mem::take(&mut self.tabs[self.current_tab]);
+        self.put_tab(tab);
+    }
+    fn edit_field(&mut self, edit: impl FnOnce(&mut String)) {
+        let pos = (self.normal_cursor.x, self.normal_cursor.y, self.insert_cursor.x);
+        let mut text = match self.insert_text.take() {
+            Some((old_pos, text)) if old_pos == pos => text,
+            //The init row of a bus without fx has no fields
+            _ => match self.cell_fields(pos.0, pos.1).get(pos.2 as usize) {
+                Some(text) => text.clone(),
+                None => return,
+            },
+        };
+        self.record();
+        edit(&mut text);
+        if self.track_mut().set_field(pos.1 as usize, pos.2 as usize, &text) && pos.1 as usize == MIXER_ROW {
+            self.send_mixer();
+        }
+        self.insert_text = Some((pos, text));
+    }
+    ///Toggles mute or solo of the tracks under the cursor or the selection
+    fn toggle_mixer(&mut self, toggle: fn(&mut project::Mixer)) {
+        let (min_x, max_x) = match self.current_mode {
+            Mode::Visual => minmax_x(self),
+            _ => (self.normal_cursor.x, self.normal_cursor.x),
+        };
+        self.record();
+        for track in &mut self.project.tracks[min_x as usize - 1..max_x as usize] {
+            toggle(&mut track.mixer);
+        }
+        self.send_mixer();
+    }
+    ///Lets the playback of this tab hear mixer changes right away
+    fn send_mixer(&self) {
+        if let Some(job) = self.render_job.as_ref().filter(|it| it.tab == self.current_tab) {
+            if let Ok(mut mixer) = job.transport.mixer.lock() {
+                *mixer = Some(self.project.tracks.iter().map(|it| it.mixer).collect());
+            }
+        }
+    }
+}
+
+const HISTORY_LIMIT: usize = 1000;
+
+struct Snapshot {
+    project: Project,
+    cursor: (u16, u16, u16),
+}
+
+///Project states before each edit, kept for the whole session
+#[derive(Default)]
+struct History {
+    undo: Vec<Snapshot>,
+    redo: Vec<Snapshot>,
+    in_insert: bool,
+}
+
+///A project open in a tab, the yank buffer is shared between tabs
+#[derive(Default)]
+struct Tab {
+    file_name: String,
+    dir: std::path::PathBuf,
+    project: Project,
+    normal_cursor: NormalCursor,
+    visual_cursor: VisualCursor,
+    insert_cursor: InsertCursor,
+    scroll: (u16, u16),
+    history: History,
+    dirty: bool,
+}
+
+impl Tab {
+    fn new() -> Self {
+        Tab {
+            dir: std::env::current_dir().unwrap_or_default(),
+            project: Project {
+                tracks: vec![
+                    Track { notes: vec![NoteRow::default(); 2], ..Track::default() },
+                    Track::default(),
+                ],
+                ..Project::default()
+            },
+            normal_cursor: NormalCursor { x: 1, y: INIT_ROW as u16 },
+            visual_cursor: VisualCursor { x: 1, y: INIT_ROW as u16 },
+            scroll: (1, 0),
+            ..Tab::default()
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -123,19 +558,13 @@ impl Widget for TableWithCells<'_> {
             Constraint::Max(3),
         ])
         .split(constr_col[1]);
-        let temp_bound = self.app.count_bound(); //TODO: don't call this fn every time
+        let col_ids = std::iter::once(0).chain(self.app.visible_cols()).collect::<Vec<u16>>();
         let constr_x = ratatui::layout::Layout::default()
             .direction(Direction::Horizontal)
-            .constraints(
-                [
-                    vec![Constraint::Max(4)],
-                    vec![Constraint::Max(14); self.app.normal_cursor.x.saturating_sub(1) as usize],
-                    vec![Constraint::Max(temp_bound as u16)] ,
-                    vec![Constraint::Max(14); self.app.cols.len() - self.app.normal_cursor.x as usize - 1],
-                ]
-                .concat(),
-            )
+            .constraints(col_ids.iter().map(|&x| Constraint::Max(self.app.col_width(x))).collect::<Vec<_>>())
             .split(constr_rows[1]);
+        let scroll_y = self.app.scroll_y as usize;
+        let view_rows = scroll_y..scroll_y + constr_rows[1].height as usize;
 
         let (max_vx, min_vx) = if self.app.normal_cursor.x >= self.app.visual_cursor.x {
             (self.app.normal_cursor.x, self.app.visual_cursor.x)
@@ -150,22 +579,37 @@ impl Widget for TableWithCells<'_> {
 
         //Highlight line
         match self.app.current_mode {
-            Mode::Normal | Mode::Insert => {
+            Mode::Normal | Mode::Insert if view_rows.contains(&(self.app.normal_cursor.y as usize)) => {
                 buf.set_span(
                     constr_col[1].x,
-                    self.app.normal_cursor.y + constr_rows[1].y,
+                    self.app.normal_cursor.y - self.app.scroll_y + constr_rows[1].y,
                     &Span::from(" ".repeat(area.width as usize)).bg(self.app.theme["bg_highlight"]),
                     area.width - 2,
                 );
             }
             _ => {}
         }
-        for (col_i, col) in self.app.cols.iter().enumerate() {
+        let cols = col_ids
+            .iter()
+            .map(|&x| {
+                let rows = view_rows.start.min(self.app.row_count(x))..view_rows.end.min(self.app.row_count(x));
+                match x {
+                    0 => self.app.lines[rows].iter().map(|it| vec![it.clone()]).collect::<Vec<_>>(),
+                    x => rows
+                        .map(|y| self.app.cell_fields(x, y as u16).into_iter().map(Span::from).collect())
+                        .collect(),
+                }
+            })
+            .collect::<Vec<Vec<Vec<Span>>>>();
+        for (pos, col) in cols.iter().enumerate() {
+            let col_i = col_ids[pos] as usize;
+            let playhead_row = self.app.playhead_row(col_i as u16);
             let constr_y = ratatui::layout::Layout::default()
                 .direction(Direction::Vertical)
                 .constraints(vec![Constraint::Max(1); col.len()])
-                .split(constr_x[col_i]);
-            for (i, el) in col.iter().enumerate() {
+                .split(constr_x[pos]);
+            for (row_i, el) in col.iter().enumerate() {
+                let i = scroll_y + row_i;
                 let curr_len = el.len();
                 let line_bound = if curr_len > 7 { 7 } else { curr_len };
                 let bounded_el = if (col_i == self.app.normal_cursor.x as usize) && (i == self.app.normal_cursor.y as usize) { &el[..] } else { &el[..line_bound] };
@@ -173,12 +617,12 @@ impl Widget for TableWithCells<'_> {
                     layout::Layout::default()
                         .direction(Direction::Horizontal)
                         .constraints(bounded_el.iter().map(|it| Constraint::Max(it.content.len() as u16 + 1)).collect::<Vec<_>>())
-                        .split(constr_y[i])
+                        .split(constr_y[row_i])
                 } else {
                     layout::Layout::default()
                         .direction(Direction::Horizontal)
                         .constraints(vec![Constraint::Max(3)])
-                        .split(constr_y[i])
+                        .split(constr_y[row_i])
                 };
                 for (ci, c) in bounded_el.iter().enumerate() {
                     let (cell_style, inside_style) = match self.app.current_mode {
@@ -203,6 +647,12 @@ impl Widget for TableWithCells<'_> {
                         }
                         _ => (Modifier::default(), Modifier::default()),
                     };
+                    let (cell_style, inside_style) = if playhead_row == Some(i) {
+                        let playhead = Style::new().bg(self.app.theme["blue7"]);
+                        (playhead.add_modifier(cell_style), playhead.add_modifier(inside_style))
+                    } else {
+                        (Style::from(cell_style), Style::from(inside_style))
+                    };
                     let c_len = if c.content.is_empty() { 1 } else { c.content.len() as u16 };
                     let printed_cell = if !c.content.is_empty() { &c.clone().patch_style(cell_style) } else { &Span::from(" ").patch_style(cell_style) };
                     buf.set_span(
@@ -212,7 +662,7 @@ impl Widget for TableWithCells<'_> {
                         c_len,
                     );
                     match ci {
-                        0 | 2 | 4 if (i > 1) && (col_i > 0) => {
+                        0 | 2 | 4 if (i >= NOTE_ROW) && (col_i > 0) => {
                             buf.set_span(
                                 constr_c[ci].x + c_len,
                                 constr_c[ci].y,
@@ -220,7 +670,7 @@ impl Widget for TableWithCells<'_> {
                                 1,
                             );
                         }
-                        ci if (i > 1) && (col_i > 0) => {
+                        ci if (i >= NOTE_ROW) && (col_i > 0) => {
                             buf.set_span(
                                 constr_c[ci].x + c_len,
                                 constr_c[ci].y,
@@ -247,329 +697,673 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    ///Render file to wav format
+    ///Render file to wav, flac or ogg picked by the output extension
     Render {
         file_path: Option<String>,
         output_path: Option<String>,
+        ///Override the sample rate stored in the project
+        #[arg(long)]
+        sample_rate: Option<u32>,
+        ///Override the bit depth stored in the project, 16 and 24 are dithered, 32 is float
+        #[arg(long, value_parser = ["16", "24", "32"])]
+        bits: Option<String>,
+        ///Override the normalization stored in the project
+        #[arg(long, value_parser = ["off", "peak", "lufs"])]
+        normalize: Option<String>,
+        ///Peak in dBFS or loudness in LUFS to normalize to, -1 and -14 by default
+        #[arg(long, requires = "normalize", allow_hyphen_values = true)]
+        target: Option<f32>,
+        ///Write one file per track to this directory instead of the mix
+        #[arg(long)]
+        stems: Option<String>,
+        ///Also write every stem before the track effects
+        #[arg(long, requires = "stems")]
+        dry: bool,
+    },
+    ///Export file to another format
+    Export {
+        format: ExportFormat,
+        file_path: Option<String>,
+        output_path: Option<String>,
+    },
+    ///Send the notes to Pure Data over UDP as they would play
+    Pd {
+        file_path: Option<String>,
+        ///Host and port of the netreceive object
+        #[arg(long, default_value = pd::DEFAULT_ADDRESS)]
+        address: String,
+        #[arg(long, value_enum, default_value_t = pd::Protocol::Fudi)]
+        protocol: pd::Protocol,
     },
 }
 
-fn render(app: &mut App) -> Vec<f32> {
-    let mut out_vec: Vec<(f32, f32)> = vec![];
-    let cur_dir = std::env::current_dir().unwrap();
-    let lib_name;
-    let comp_status = if cur_dir.join("cargolib/").exists() {
-//cargo run --release --manifest-path=iaue/Cargo.toml
-    let full_path_lib = std::env::current_dir()
-        .unwrap()
-            .join("cargolib/");
-        let out = std::process::Command::new("cargo")
-            .arg("build")
-            .arg("--release")
-            .arg("--manifest-path=cargolib/Cargo.toml")
-            .stdout(Stdio::null())
-            .stderr(Stdio::piped())
-            .output()
-            //.inspect_err(|e| app.command_buf = e.to_string())
-            .unwrap();
-    lib_name = std::path::Path::new(&full_path_lib)
-            .join("target/")
-            .join("release/")
-            .join("libcargolib.so")
-            .canonicalize()
-            .unwrap();
-        out
-    }
-else {
-    let full_path_lib = std::env::current_dir()
-        .unwrap()
-        .join(app.file_name.clone() + ".rs");
-    let out = std::process::Command::new("rustc")
-        .arg("-C")
-        .arg("target-feature=-crt-static")
-        .arg("--crate-type")
-        .arg("cdylib")
-        .arg(&full_path_lib)
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    ///Standard MIDI File with one track per column
+    Midi,
+    ///DAWproject archive with one note clip per column
+    Dawproject,
+    ///SunVox project with one pattern and a Sampler module of rendered notes per column
+    Sunvox,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Midi => "mid",
+            ExportFormat::Dawproject => "dawproject",
+            ExportFormat::Sunvox => "sunvox",
+        }
+    }
+}
+
+enum RenderStage {
+    Compiling,
+    //Track counted from 1, number of tracks and percent of the song
+    Rendering(usize, usize, usize),
+    Mixing(usize),
+    Sending,
+}
+
+impl std::fmt::Display for RenderStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderStage::Compiling => write!(f, "Compiling"),
+            RenderStage::Rendering(track, tracks, percent) => write!(f, "Rendering track {} of {}, {}%", track, tracks, percent),
+            RenderStage::Mixing(percent) => write!(f, "Mixing {}%", percent),
+            RenderStage::Sending => write!(f, "Sending to Pd"),
+        }
+    }
+}
+
+///Note rows to play, playback starts at `rows.0` of the first track that has it
+#[derive(Clone, Copy)]
+struct PlayRange {
+    tracks: (usize, usize),
+    rows: (usize, usize),
+    looped: bool,
+}
+
+#[derive(Clone)]
+enum RenderAction {
+    Play(PlayRange),
+    Save(std::path::PathBuf),
+    //Directory and whether the tracks are written before their effects too
+    Stems(std::path::PathBuf, bool),
+}
+
+enum RenderEvent {
+    Stage(RenderStage),
+    Compiled(String),
+    Timeline(Vec<Vec<engine::RowSpan>>),
+    Done(std::result::Result<String, String>),
+}
+
+#[derive(Default)]
+struct Transport {
+    paused: AtomicBool,
+    //Frame the output device is playing right now
+    position: AtomicUsize,
+    //Mixer strips changed while playing, taken by the engine before its next block
+    mixer: Mutex<Option<Vec<project::Mixer>>>,
+}
+
+struct RenderJob {
+    cancel: Arc<AtomicBool>,
+    receiver: mpsc::Receiver<RenderEvent>,
+    transport: Arc<Transport>,
+    //Set once playback starts
+    timeline: Option<Vec<Vec<engine::RowSpan>>>,
+    looped: bool,
+    sample_rate: usize,
+    //Tab of the project being rendered
+    tab: usize,
+}
+
+const RENDER_CANCELLED: &str = "Render cancelled.";
+//How often the render status follows the engine from track to track while the percent stays the same
+const STAGE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+const BUS_NOTES: &str = "Bus tracks have no notes.";
+
+fn run_compiler(command: &mut Command, cancel: &AtomicBool) -> std::result::Result<(std::process::ExitStatus, String), String> {
+    let mut child = command
         .stdout(Stdio::null())
         .stderr(Stdio::piped())
-        .output()
-        //.inspect_err(|e| app.command_buf = e.to_string())
-    .unwrap();
-    lib_name = std::path::Path::new(&("lib".to_string().to_owned() + &app.file_name + ".so"))
+        .spawn()
+        .map_err(|e| format!("Can't run {:?}: {}", command.get_program(), e))?;
+    let mut stderr = child.stderr.take();
+    let reader = std::thread::spawn(move || {
+        let mut out = Vec::new();
+        if let Some(stderr) = stderr.as_mut() {
+            let _ = stderr.read_to_end(&mut out);
+        }
+        String::from_utf8_lossy(&out).to_string()
+    });
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            return Ok((status, reader.join().unwrap_or_default()));
+        }
+        if cancel.load(Ordering::Relaxed) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(RENDER_CANCELLED.to_string());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+}
+
+///Returns None when the project has no user code, only built-in instruments are used then
+fn compile_lib(file_name: &str, cancel: &AtomicBool) -> std::result::Result<(Option<std::path::PathBuf>, String), String> {
+    let cur_dir = std::env::current_dir().map_err(|e| e.to_string())?;
+    if !cur_dir.join("cargolib/").exists() && !cur_dir.join(file_name.to_string() + ".rs").exists() {
+        return Ok((None, String::new()));
+    }
+    let ((status, err_out), lib_name) = if cur_dir.join("cargolib/").exists() {
+        //cargo run --release --manifest-path=iaue/Cargo.toml
+        let full_path_lib = cur_dir.join("cargolib/");
+        let out = run_compiler(
+            Command::new("cargo")
+                .arg("build")
+                .arg("--release")
+                .arg("--manifest-path=cargolib/Cargo.toml"),
+            cancel,
+        )?;
+        (out, full_path_lib.join("target/").join("release/").join("libcargolib.so"))
+    } else {
+        let full_path_lib = cur_dir.join(file_name.to_string() + ".rs");
+        let out = run_compiler(
+            Command::new("rustc")
+                .arg("-C")
+                .arg("target-feature=-crt-static")
+                .arg("--crate-type")
+                .arg("cdylib")
+                .arg(&full_path_lib),
+            cancel,
+        )?;
+        (out, cur_dir.join("lib".to_string() + file_name + ".so"))
+    };
+    if !status.success() {
+        return Err(err_out);
+    }
+    let lib_name = lib_name
         .canonicalize()
-        .unwrap();
-        out
+        .map_err(|_| format!("Can't find library {}.", lib_name.display()))?;
+    Ok((Some(lib_name), err_out))
+}
+
+fn start_render(app: &mut App, action: RenderAction) {
+    let project = app.project.clone();
+    let file_name = app.file_name.clone();
+    let names = project.tracks.iter().map(|it| it.name.clone()).collect::<Vec<_>>();
+    let audio_params = OutputDeviceParameters {
+        sample_rate: project.settings.sample_rate as usize,
+        ..app.audio_params
     };
-    let err_out = std::str::from_utf8(&comp_status.stderr).unwrap_or("meh");
-    app.command_buf = err_out.to_string();
-
-    let mut output: Vec<Vec<(f32, f32)>> = vec![Vec::new(); app.cols.len()];
-    let mut unique_fn: Vec<String> = Vec::new();
-    for col in &app.cols[1..] {
-        for el in &col[2..] {
-            if !unique_fn.contains(&el[6].to_string()) {
-                unique_fn.push(el[6].to_string().clone());
+    let looped = matches!(action, RenderAction::Play(PlayRange { looped: true, .. }));
+    spawn_job(app, looped, move |sender, transport, cancel| {
+        let progress = |stage| {
+            let _ = sender.send(RenderEvent::Stage(stage));
+        };
+        load_engine(&project, &file_name, &progress, cancel).and_then(|(mut engine, log)| {
+            let _ = sender.send(RenderEvent::Compiled(log));
+            match action {
+                RenderAction::Play(range) => {
+                    let timeline = engine.timeline();
+                    seek_range(&mut engine, &timeline, range);
+                    let _ = sender.send(RenderEvent::Timeline(timeline));
+                    play(&mut engine, audio_params, transport, cancel).map(|_| String::new())
+                }
+                RenderAction::Save(path) => {
+                    write_audio(&mut engine, &path, &project.settings, &tags(&project, &file_name), &progress, cancel)
+                }
+                RenderAction::Stems(dir, dry) => write_stems(&mut engine, &names, &dir, dry, &project.settings, &progress, cancel),
+            }
+        })
+    });
+}
+
+///Sends the notes of the project to Pure Data as a job, Esc stops it like playback
+fn start_send(app: &mut App, address: String, protocol: pd::Protocol) {
+    let project = app.project.clone();
+    spawn_job(app, false, move |sender, _, cancel| {
+        let _ = sender.send(RenderEvent::Stage(RenderStage::Sending));
+        pd::send(&project, &address, protocol, cancel).map(|sent| format!("Sent {} notes to {}", sent, address))
+    });
+}
+
+///Runs `work` on its own thread, it reports through the sender and its result ends the job
+fn spawn_job<F>(app: &mut App, looped: bool, work: F)
+where
+    F: FnOnce(&mpsc::Sender<RenderEvent>, &Arc<Transport>, &AtomicBool) -> std::result::Result<String, String> + Send + 'static,
+{
+    if app.render_job.is_some() {
+        app.command_buf = "Render is already running.".to_string();
+        return;
+    }
+    let (sender, receiver) = mpsc::channel();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let worker_cancel = cancel.clone();
+    let transport = Arc::new(Transport::default());
+    let worker_transport = transport.clone();
+    std::thread::spawn(move || {
+        let result = work(&sender, &worker_transport, &worker_cancel);
+        let _ = sender.send(RenderEvent::Done(result));
+    });
+    app.render_status = RenderStage::Compiling.to_string();
+    app.render_job = Some(RenderJob {
+        cancel,
+        receiver,
+        transport,
+        timeline: None,
+        looped,
+        sample_rate: app.project.settings.sample_rate as usize,
+        tab: app.current_tab,
+    });
+}
+
+///Plays from the cursor row, in visual mode loops the selected rows
+fn play_range(app: &App) -> PlayRange {
+    match app.current_mode {
+        Mode::Visual => {
+            let ((min_x, max_x), (min_y, max_y)) = (minmax_x(app), minmax_y(app));
+            PlayRange {
+                tracks: (min_x.saturating_sub(1) as usize, max_x.saturating_sub(1) as usize),
+                rows: ((min_y as usize).saturating_sub(NOTE_ROW), (max_y as usize).saturating_sub(NOTE_ROW)),
+                looped: true,
             }
         }
+        _ => {
+            let track = app.normal_cursor.x.saturating_sub(1) as usize;
+            let row = (app.normal_cursor.y as usize).saturating_sub(NOTE_ROW);
+            PlayRange { tracks: (track, track), rows: (row, usize::MAX), looped: false }
+        }
     }
-    let mut unique_fx: Vec<String> = Vec::new();
-    for col in &app.cols[1..] {
-        for el in &col[1][3..] {
-            if !unique_fx.contains(&el.to_string()) {
-                unique_fx.push(el.to_string().clone());
+}
+
+fn toggle_pause(app: &mut App) {
+    if let Some(job) = app.render_job.as_ref().filter(|it| it.timeline.is_some()) {
+        job.transport.paused.fetch_xor(true, Ordering::Relaxed);
+    }
+}
+
+fn cancel_render(app: &mut App) {
+    if let Some(job) = &app.render_job {
+        job.cancel.store(true, Ordering::Relaxed);
+        app.render_status = "Cancelling".to_string();
+    }
+}
+
+fn poll_render(app: &mut App) {
+    let Some(job) = &mut app.render_job else { return };
+    let mut done = None;
+    loop {
+        match job.receiver.try_recv() {
+            Ok(RenderEvent::Stage(stage)) => app.render_status = stage.to_string(),
+            Ok(RenderEvent::Compiled(log)) => app.command_buf = log,
+            Ok(RenderEvent::Timeline(timeline)) => job.timeline = Some(timeline),
+            Ok(RenderEvent::Done(result)) => {
+                done = Some(result);
+                break;
+            }
+            Err(mpsc::TryRecvError::Empty) => break,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                done = Some(Err("Render stopped unexpectedly.".to_string()));
+                break;
             }
         }
     }
-    let mut fns = std::collections::HashMap::new();
-    let mut fxes_fns = std::collections::HashMap::new();
-    fn f1(_f: f32, l: f32, _v: f32, t: usize, _p: &[f32]) -> Vec<(f32, f32)> {
-        vec![(0.0, 0.0); (l * t as f32) as usize]
+    let Some(result) = done else {
+        if job.timeline.is_some() && !job.cancel.load(Ordering::Relaxed) {
+            let seconds = job.transport.position.load(Ordering::Relaxed) as f32 / job.sample_rate.max(1) as f32;
+            let state = if job.transport.paused.load(Ordering::Relaxed) { "Paused" } else { "Playing" };
+            app.render_status = format!("{} {:.1}s{}", state, seconds, if job.looped { " (loop)" } else { "" });
+        }
+        return;
+    };
+    app.render_job = None;
+    app.render_status.clear();
+    match result {
+        Ok(message) if message.is_empty() => {}
+        Ok(message) => app.command_buf = message,
+        Err(log) => app.command_buf = log,
+    }
+}
 
+///Compiles and loads the user library, returns the engine and compiler output
+fn load_engine(
+    project: &Project,
+    file_name: &str,
+    progress: &dyn Fn(RenderStage),
+    cancel: &AtomicBool,
+) -> std::result::Result<(engine::Engine, String), String> {
+    progress(RenderStage::Compiling);
+    let (lib_name, log) = compile_lib(file_name, cancel)?;
+    let mut unique_fn: Vec<u32> = Vec::new();
+    for track in &project.tracks {
+        for note in &track.notes {
+            if !unique_fn.contains(&note.instrument) {
+                unique_fn.push(note.instrument);
+            }
+        }
     }
-    if comp_status.status.success() {
-        unsafe {
-            let lib = libloading::Library::new(lib_name).unwrap();
-            for el in unique_fn {
-                let f0 = lib.get::<libloading::Symbol<
-                    unsafe extern "C" fn(f32, f32, f32, usize, &[f32]) -> Vec<(f32, f32)>,
-                >>(("f".to_string() + &el).as_bytes());
-                fns.insert(el.clone(), f0);
+    let mut unique_fx: Vec<u32> = Vec::new();
+    for track in &project.tracks {
+        for fx in &track.init.fx {
+            if !unique_fx.contains(&fx.id) {
+                unique_fx.push(fx.id);
             }
-            for el in unique_fx {
-                let f0 = lib.get::<libloading::Symbol<
-                    unsafe extern "C" fn(&[(f32, f32)], usize, &[f32], &[Vec<(f32, f32)>]) -> Vec<(f32, f32)>,
-                >>(("fx".to_string() + &el).as_bytes());
-                fxes_fns.insert(el.clone(), f0);
+        }
+    }
+    let plugin = match lib_name {
+        Some(lib_name) => Some(plugin::Plugin::load(&lib_name, &unique_fn, &unique_fx)?),
+        None => None,
+    };
+    let samples = project
+        .samples
+        .iter()
+        .map(|it| instruments::SampleData::load(it).map(Arc::new))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    engine::Engine::new(project, plugin, samples).map(|engine| (engine, log))
+}
+
+///Pulls blocks from the engine and hands them to `write` with their start frame until the song ends.
+///Reports the track or the mixing the engine is on with the percent of the song, when either changes and otherwise every `STAGE_INTERVAL`
+fn run_engine(
+    engine: &mut engine::Engine,
+    progress: &dyn Fn(RenderStage),
+    cancel: &AtomicBool,
+    mut write: impl FnMut(&mut engine::Engine, &[Frame]) -> std::result::Result<(), String>,
+) -> std::result::Result<(), String> {
+    let mut block = vec![Frame::default(); engine::BLOCK_SIZE];
+    let tracks = engine.track_count();
+    let mut last = None;
+    let mut reported = std::time::Instant::now();
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(RENDER_CANCELLED.to_string());
+        }
+        let percent = engine.position() * 100 / engine.len().max(1);
+        let n = engine.next_block(&mut block, &mut |stage| {
+            if last == Some((stage, percent)) && reported.elapsed() < STAGE_INTERVAL {
+                return;
             }
-            for (i, col) in app.cols[1..].iter().enumerate() {
-                let (mut fs, mut ls, mut vs) = (440.0, 1.0, 1.0);
-                let mut fxes = Vec::new();
-                let mut fx_params = Vec::new();
-                for (i_el, el) in col[1..].iter().enumerate() {
-                    if i_el == 0 {
-                        let el_iter = &mut el.iter();
-                        let elems: Vec<_> = el_iter
-                            .take(3)
-                            .map(|it| str::parse::<f32>(&it.content).unwrap_or(0.0))
-                            .collect();
-                        (fs, ls, vs) = (elems[0], elems[1], elems[2]);
-                        let fx_and_params = el_iter.map(|it| &it.content).collect::<Vec<_>>();
-                        for fx in fx_and_params.chunks(2)
-                        {
-                            fxes.push(fx[0]);
-                            fx_params.push(fx[1].split(',').map(|it| it.parse::<f32>().unwrap_or(0.0)).collect::<Vec<f32>>());
-                        }
-                    } else {
-                        let mut pushed_args = Vec::new();
-                        let el_iter = &mut el.iter();
-                        let elems: Vec<_> = el_iter.take(6).collect();
-                        let mut vec_args = Vec::with_capacity(3);
-                        for indx in 0..3 {
-                            vec_args.push(
-                                str::parse::<f32>(&elems[indx * 2].content).unwrap_or(0.0) / str::parse::<f32>(&elems[indx * 2 + 1].content).unwrap_or(0.0),
-                            );
-                        }
-                        let (f, l, v) = (vec_args[0], vec_args[1], vec_args[2]);
-                        let (old_f, old_l, old_v) = (fs, ls, vs); 
-                        (fs, ls, vs) = (fs * f, ls * l, v * vs);
-                        let (mut new_f, mut new_l, mut new_v) = (fs, ls, vs); 
-                        let (mut fc, mut lc,  mut vc) = (new_f, new_l, new_v);
-                        let pushed_fn = &fns[&el_iter.next().unwrap_or(&Span::from("0")).content.to_string()];
-                        let mut note_repeat = 1;
-                        let mut slice_param = 1.0;
-                        let mut fx_params_slice = Vec::new();
-                        for note_param in el_iter.as_slice().chunks(2) {
-                            let note_fx = &note_param[0].content;
-                            let fx_args = &note_param[1].content.split(',').map(|it| it.to_string()).collect::<Vec<_>>();
-                            match note_fx.to_string().as_str() {
-                                "0" => {
-                                    (fc, lc, vc) = ( 
-                                    fc * fx_args.first().unwrap_or(&"1".to_string()).split("/").map(|it| it.parse::<f32>().unwrap_or(1.0)).reduce(|x, y| x / y).unwrap_or(1.0), 
-                                    lc, 
-                                    vc * fx_args.get(1).unwrap_or(&"1".to_string()).split("/").map(|it| it.parse::<f32>().unwrap_or(1.0)).reduce(|x, y| x / y).unwrap_or(1.0));
-                                    pushed_args.push((fc, lc, vc));
-                                },
-                                "1" => {
-                                    pushed_args.push((
-                                    fs * fx_args.first().unwrap_or(&"1".to_string()).split("/").map(|it| it.parse::<f32>().unwrap_or(1.0)).reduce(|x, y| x / y).unwrap_or(1.0), 
-                                    ls, 
-                                    vs * fx_args.get(1).unwrap_or(&"1".to_string()).split("/").map(|it| it.parse::<f32>().unwrap_or(1.0)).reduce(|x, y| x / y).unwrap_or(1.0)))
-                                },
-                                "2" => { note_repeat *= fx_args.first().unwrap_or(&"1".to_string()).parse::<usize>().unwrap_or(1); }
-
-                                "3" => { fx_params_slice.extend(fx_args.iter().map(|it| it.parse::<f32>().unwrap_or(0.0))); },
-
-                                "4" => { new_f = fx_args.first().unwrap_or(&fs.to_string()).parse::<f32>().unwrap_or(fs);
-                                    fs = new_f;
-                                },
-
-                                "5" => { new_l = fx_args.first().unwrap_or(&ls.to_string()).parse::<f32>().unwrap_or(ls);
-                                    ls = new_l;
-                                },
-
-                                "6" => { new_v = fx_args.first().unwrap_or(&vs.to_string()).parse::<f32>().unwrap_or(vs);
-                                    vs = new_v;
-                                },
-
-                                "7" => { (new_f, new_l, new_v) = (old_f, old_l, old_v); },
-                                "8" => { 
-                                    note_repeat *= fx_args.first().unwrap_or(&"1".to_string()).parse::<usize>().unwrap_or(1); 
-                                    slice_param = if note_repeat == 0 { 1.0 } else { note_repeat as f32 };
-                                },
-                                "9" => {
-                                    let mut bound = fx_args.first().unwrap_or(&"1".to_string()).parse::<usize>().unwrap_or(1);
-                                    if bound == 0 { bound = 1 };
-                                    let down_bound = fx_args.get(1).unwrap_or(&"20".to_string()).parse::<f32>().unwrap_or(20.0);
-                                    let up_bound = fx_args.get(2).unwrap_or(&"20000".to_string()).parse::<f32>().unwrap_or(20_000.0);
-                                    let mut rand_iter = core::iter::repeat_with(|| fastrand::usize(1..=bound));
-                                    fs *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
-                                    let mut it = 0;
-                                    while (fs < down_bound) && (it < 8) {
-                                        fs *= 2.0;
-                                        it += 1;
-                                    }
-                                    while (fs > up_bound) && (it < 8) {
-                                        fs /= 2.0;
-                                        it += 1;
-                                    }
-                                    new_f = fs;
-                                },
-                                "10" => {
-                                    let mut bound = fx_args.first().unwrap_or(&"1".to_string()).parse::<usize>().unwrap_or(1);
-                                    if bound == 0 { bound = 1 };
-                                    let down_bound = fx_args.get(1).unwrap_or(&"0.01".to_string()).parse::<f32>().unwrap_or(0.01);
-                                    let up_bound = fx_args.get(2).unwrap_or(&"10".to_string()).parse::<f32>().unwrap_or(10.0);
-                                    let mut rand_iter = core::iter::repeat_with(|| fastrand::usize(1..=bound));
-                                    ls *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
-                                    let mut it = 0;
-                                    while (ls < down_bound) && (it < 8) {
-                                        ls *= 2.0;
-                                        it += 1;
-                                    }
-                                    while (ls > up_bound) && (it < 8) {
-                                        ls /= 2.0;
-                                        it += 1;
-                                    }
-                                    new_l = ls;
-                                },
-                                "11" => {
-                                    let mut bound = fx_args.first().unwrap_or(&"1".to_string()).parse::<usize>().unwrap_or(1);
-                                    if bound == 0 { bound = 1 };
-                                    let down_bound = fx_args.get(1).unwrap_or(&"0.1".to_string()).parse::<f32>().unwrap_or(0.1);
-                                    let up_bound = fx_args.get(2).unwrap_or(&"1".to_string()).parse::<f32>().unwrap_or(1.0);
-                                    let mut rand_iter = core::iter::repeat_with(|| fastrand::usize(1..=bound));
-                                    vs *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
-                                    let mut it = 0;
-                                    while (vs < down_bound) && (it < 8) {
-                                        vs *= 2.0;
-                                        it += 1;
-                                    }
-                                    while (vs > up_bound) && (it < 8){
-                                        vs /= 2.0;
-                                        it += 1;
-                                    }
-                                    new_v = vs;
-                                },
-                                "12" => {
-                                    let mut bound = fx_args.first().unwrap_or(&"1".to_string()).parse::<usize>().unwrap_or(1);
-                                    if bound == 0 { bound = 1 };
-                                    let mut rand_iter = core::iter::repeat_with(|| fastrand::usize(1..=bound));
-                                    fs *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
-                                    ls *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
-                                    vs *= rand_iter.next().unwrap_or(1) as f32 / rand_iter.next().unwrap_or(1) as f32;
-                                    new_f = fs;
-                                    new_l = ls;
-                                    new_v = vs;
-                                },
-
-                                _ => {}
-                            }
-                        }
-                        pushed_args.push((fs, ls, vs));
-                        (fs, ls, vs) = (new_f, new_l, new_v);
-                        let mut temp_vec: Vec<Vec<(f32, f32)>> = Vec::new();
-                        for (fs, ls, vs) in pushed_args {
-                            match pushed_fn {
-                                Ok(val) => {
-                                    let out_tuple = val(fs, ls / slice_param, vs, 44100, fx_params_slice.as_slice());
-                                    temp_vec.push(out_tuple);
-                                }
-                                Err(_) => {
-                                    let out_tuple = f1(fs, ls / slice_param, vs, 44100, fx_params_slice.as_slice());
-                                    temp_vec.push(out_tuple);
-                                }
-                            }
-                        }
-                        let len_of_note = temp_vec[0].len();
-                        let mut sum_vec = vec![(0.0, 0.0); len_of_note];
-                            for el in temp_vec {
-                                for (i, sample) in el.iter().enumerate() {
-                                    sum_vec[i].0 += sample.0;
-                                    sum_vec[i].1 += sample.1;
-                                }
-                            }
-                        let out_note = sum_vec.into_iter().cycle().take(len_of_note * note_repeat);
-                        output[i].extend(out_note);
-                    }
+            progress(match stage {
+                engine::Stage::Track(track) => RenderStage::Rendering(track + 1, tracks, percent),
+                engine::Stage::Mixing => RenderStage::Mixing(percent),
+            });
+            last = Some((stage, percent));
+            reported = std::time::Instant::now();
+        });
+        if n == 0 {
+            return Ok(());
+        }
+        write(engine, &block[..n])?;
+    }
+}
+
+///Writes the mix in the format of the extension with the bit depth and normalization of `settings`, returns the status message, which warns when the mix clips
+fn write_audio(
+    engine: &mut engine::Engine,
+    path: &std::path::Path,
+    settings: &Settings,
+    tags: &[(String, String)],
+    progress: &dyn Fn(RenderStage),
+    cancel: &AtomicBool,
+) -> std::result::Result<String, String> {
+    let error = |e: std::io::Error| format!("Can't write {}: {}", path.display(), e);
+    let sample_rate = engine.sample_rate();
+    let mut writer = encoder::Encoder::create(path, sample_rate as u32, settings.bit_depth, tags).map_err(error)?;
+    let result = match settings.normalize {
+        Normalize::Off => run_engine(engine, progress, cancel, |_, block| writer.write(block).map_err(error)),
+        normalize => {
+            // The gain depends on the whole song, so it is kept in memory until the end.
+            let mut frames = Vec::with_capacity(engine.len());
+            run_engine(engine, progress, cancel, |_, block| {
+                frames.extend_from_slice(block);
+                Ok(())
+            })
+            .and_then(|_| {
+                let gain = loudness::normalize_gain(&frames, sample_rate, normalize);
+                for frame in &mut frames {
+                    frame.left *= gain;
+                    frame.right *= gain;
                 }
-                for (idx, fx) in fxes.iter().enumerate() {
-                    let cur_fx = &fxes_fns[&fx.to_string()];
-                    match cur_fx {
-                        Ok(val) => {
-                            let out_tuple = val(output[i].as_slice(), 44100, fx_params[idx].as_slice(), output.as_slice());
-                            output[i] = out_tuple;
-                        }
-                        Err(_) => {
-                        }
+                writer.write(&frames).map_err(error)
+            })
+        }
+    };
+    if let Err(log) = result {
+        drop(writer);
+        let _ = fs::remove_file(path);
+        return Err(log);
+    }
+    let clipped = writer.clipped();
+    writer.finish().map_err(error)?;
+    Ok(match clipped {
+        0 => format!("Saved to {}", path.display()),
+        clipped => format!("Saved to {}, {} samples clip.", path.display(), clipped),
+    })
+}
+
+///Tags of encoded files, the title is the file name when the project has none and every track name gets a TRACKNAME tag
+fn tags(project: &Project, file_name: &str) -> Vec<(String, String)> {
+    let title = match project.metadata.title.as_str() {
+        "" => file_name.trim_end_matches(".tr").to_string(),
+        title => title.to_string(),
+    };
+    let mut tags = vec![("TITLE".to_string(), title)];
+    if !project.metadata.author.is_empty() {
+        tags.push(("ARTIST".to_string(), project.metadata.author.clone()));
+    }
+    if !project.metadata.comment.is_empty() {
+        tags.push(("COMMENT".to_string(), project.metadata.comment.clone()));
+    }
+    tags.extend(project.tracks.iter().map(|it| ("TRACKNAME".to_string(), it.name.clone())));
+    tags
+}
+
+///Writes every track after its effects to `dir`, named after the track, with `dry` also before them, in the bit depth of `settings`.
+///Normalization applies to every stem on its own. Returns the status message, which names the stems that clip
+fn write_stems(
+    engine: &mut engine::Engine,
+    names: &[String],
+    dir: &std::path::Path,
+    dry: bool,
+    settings: &Settings,
+    progress: &dyn Fn(RenderStage),
+    cancel: &AtomicBool,
+) -> std::result::Result<String, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+    let mut stems = Vec::new();
+    for (track, name) in stem_names(names).into_iter().enumerate() {
+        stems.push((track, false, dir.join(format!("{}.wav", name))));
+        if dry {
+            stems.push((track, true, dir.join(format!("{} dry.wav", name))));
+        }
+    }
+    if dry {
+        engine.keep_dry();
+    }
+    let error = |path: &std::path::Path, e: std::io::Error| format!("Can't write {}: {}", path.display(), e);
+    let sample_rate = engine.sample_rate();
+    let mut writers = Vec::new();
+    for (_, _, path) in &stems {
+        writers.push(encoder::Encoder::create(path, sample_rate as u32, settings.bit_depth, &[]).map_err(|e| error(path, e))?);
+    }
+    // Like the mix, normalized stems are kept in memory until the gain of each is known.
+    let mut kept = vec![Vec::new(); if settings.normalize == Normalize::Off { 0 } else { stems.len() }];
+    let mut buffer = vec![Frame::default(); engine::BLOCK_SIZE];
+    let result = run_engine(engine, progress, cancel, |engine, block| {
+        for (i, ((track, dry, path), writer)) in stems.iter().zip(&mut writers).enumerate() {
+            for (dst, src) in buffer.iter_mut().zip(engine.track_block(*track, *dry)) {
+                *dst = Frame { left: engine::sanitize(src.left), right: engine::sanitize(src.right) };
+            }
+            match kept.get_mut(i) {
+                Some(frames) => frames.extend_from_slice(&buffer[..block.len()]),
+                None => writer.write(&buffer[..block.len()]).map_err(|e| error(path, e))?,
+            }
+        }
+        Ok(())
+    })
+    .and_then(|_| {
+        for ((frames, (_, _, path)), writer) in kept.iter_mut().zip(&stems).zip(&mut writers) {
+            let gain = loudness::normalize_gain(frames, sample_rate, settings.normalize);
+            for frame in frames.iter_mut() {
+                frame.left *= gain;
+                frame.right *= gain;
+            }
+            writer.write(frames).map_err(|e| error(path, e))?;
+        }
+        Ok(())
+    });
+    if let Err(log) = result {
+        drop(writers);
+        for (_, _, path) in &stems {
+            let _ = fs::remove_file(path);
+        }
+        return Err(log);
+    }
+    let mut clips = Vec::new();
+    for ((_, _, path), writer) in stems.iter().zip(writers) {
+        if writer.clipped() > 0 {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            clips.push(format!("{} samples clip in {}", writer.clipped(), file_name));
+        }
+        writer.finish().map_err(|e| error(path, e))?;
+    }
+    if clips.is_empty() {
+        Ok(format!("Saved {} stems to {}", stems.len(), dir.display()))
+    } else {
+        Ok(format!("Saved {} stems to {}, {}.", stems.len(), dir.display(), clips.join(", ")))
+    }
+}
+
+///File names for the tracks, without path separators and unique, unnamed tracks are called by their number
+fn stem_names(names: &[String]) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        let name = name.trim().replace(['/', '\\', ':'], "_");
+        let mut name = if name.is_empty() || name.starts_with('.') { format!("track{}", i + 1) } else { name };
+        if result.contains(&name) {
+            name = format!("{} {}", name, i + 1);
+        }
+        result.push(name);
+    }
+    result
+}
+
+///Moves the engine to the start of the range, loops it if asked
+fn seek_range(engine: &mut engine::Engine, timeline: &[Vec<engine::RowSpan>], range: PlayRange) {
+    let tracks = timeline
+        .iter()
+        .skip(range.tracks.0)
+        .take(range.tracks.1.saturating_sub(range.tracks.0) + 1)
+        .flatten();
+    let start = tracks.clone().filter(|it| it.row >= range.rows.0).map(|it| it.start).min().unwrap_or(engine.len());
+    engine.seek(start);
+    if range.looped {
+        let end = tracks.filter(|it| it.row <= range.rows.1).map(|it| it.end).max().unwrap_or(start);
+        engine.set_loop(start, end);
+    }
+}
+
+///Streams the engine to the output device, blocks until playback ends or is cancelled
+fn play(
+    engine: &mut engine::Engine,
+    audio_params: OutputDeviceParameters,
+    transport: &Arc<Transport>,
+    cancel: &AtomicBool,
+) -> std::result::Result<(), String> {
+    // A few blocks ahead of the device is enough, the engine renders faster than real time.
+    let (sender, receiver) = mpsc::sync_channel::<(usize, Vec<Frame>)>(4);
+    let finished = Arc::new(AtomicBool::new(false));
+    let device_finished = finished.clone();
+    let device_transport = transport.clone();
+    let mut block: Vec<Frame> = Vec::new();
+    let mut block_start = 0;
+    let mut pos = 0;
+    let _device = run_output_device(audio_params, move |data| {
+        if device_transport.paused.load(Ordering::Relaxed) {
+            data.fill(0.0);
+            return;
+        }
+        for frame in data.chunks_mut(audio_params.channels_count) {
+            if pos >= block.len() {
+                match receiver.try_recv() {
+                    Ok((start, next)) => {
+                        (block_start, block) = (start, next);
+                        pos = 0;
                     }
+                    Err(mpsc::TryRecvError::Empty) => {}
+                    Err(mpsc::TryRecvError::Disconnected) => device_finished.store(true, Ordering::Relaxed),
                 }
             }
-            let max_len = output
-                .iter()
-                .map(|it| it.len())
-                .max()
-                .unwrap_or(0);
-            out_vec = vec![(0.0, 0.0); max_len];
-            for column in output {
-                for (i, el) in column.iter().enumerate() {
-                    out_vec[i].0 += el.0;
-                    out_vec[i].1 += el.1;
-                }
+            let sample = block.get(pos).copied().unwrap_or_default();
+            if pos < block.len() {
+                device_transport.position.store(block_start + pos, Ordering::Relaxed);
             }
-            //let mut out_vec_iter = out_vec.into_iter();
-            //fn_status = format!("{}, {}, {}, {}", ft, lt, vt, (max_len / 44100) as f32);
-        }
-    }
-    out_vec.iter().map(|&(it, y)| if it == f32::INFINITY { (f32::MAX, y) }
-        else if it == f32::NEG_INFINITY { (f32::MIN, y) }
-        else if it.is_nan() { (0.0, y) }
-        else { (it, y) }
-    )
-        .map(|(x, it)| if it == f32::INFINITY { (x, f32::MAX) }
-            else if it == f32::NEG_INFINITY { (x, f32::MIN) }
-            else if it.is_nan() { (x, 0.0) }
-            else { (x, it) }
-        )
-    .flat_map(|(x, y)| [x, y])
-    .collect::<Vec<_>>()
+            pos += 1;
+            for (channel, out) in frame.iter_mut().enumerate() {
+                *out = if channel % 2 == 0 { sample.left } else { sample.right };
+            }
+        }
+    })
+    .map_err(|e| format!("Can't open audio device: {}", e))?;
+    run_engine(engine, &|_| {}, cancel, |engine, block| {
+        if let Some(mixers) = transport.mixer.lock().ok().and_then(|mut it| it.take()) {
+            engine.set_mixer(&mixers);
+        }
+        let mut item = (engine.position() - block.len(), block.to_vec());
+        loop {
+            match sender.try_send(item) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::TrySendError::Full(back)) => item = back,
+                Err(mpsc::TrySendError::Disconnected(_)) => return Err("Audio device stopped.".to_string()),
+            }
+            // The device doesn't take blocks while paused, so cancel has to be checked here too.
+            if cancel.load(Ordering::Relaxed) {
+                return Err(RENDER_CANCELLED.to_string());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+    })?;
+    drop(sender);
+    while !finished.load(Ordering::Relaxed) {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(RENDER_CANCELLED.to_string());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    Ok(())
 }
 
 fn render_and_save_file(app: &mut App, file_name: String) {
-    use std::fs::File;
     use std::path::absolute;
     use std::path::Path;
-    let out_file = render(app);
+    let mut words = file_name.split_whitespace().peekable();
+    if words.next_if_eq(&"stems").is_some() {
+        let dry = words.next_if_eq(&"--dry").is_some();
+        let dir = words.collect::<Vec<_>>().join(" ");
+        let dir = if dir.is_empty() { app.file_name.clone() + "_stems" } else { dir };
+        match absolute(Path::new(&dir)) {
+            Ok(full_path) => start_render(app, RenderAction::Stems(full_path, dry)),
+            Err(_) => app.command_buf = format!("Can't write {}.", dir),
+        }
+        return;
+    }
     let new_file_name = if file_name.is_empty() { app.file_name.clone() + ".wav" } else { file_name };
-    let full_path = absolute(Path::new(&new_file_name)).unwrap().to_path_buf();
-    let mut file = File::create(full_path).unwrap();
-    let header = wav_io::new_stereo_header();
-    let _ = wav_io::write_to_file(&mut file, &header, &out_file);
-    app.command_buf = format!("Saved to {}", new_file_name); //TODO:
+    match absolute(Path::new(&new_file_name)) {
+        Ok(full_path) => start_render(app, RenderAction::Save(full_path)),
+        Err(_) => app.command_buf = format!("Can't write {}.", new_file_name),
+    }
+}
+
+fn read_project(path: &std::path::Path) -> std::result::Result<(Project, u32), String> {
+    let data = fs::read_to_string(path).map_err(|_| format!("Can't find file {}.", path.display()))?;
+    Project::from_json(&data).map_err(|e| format!("Can't parse file {}: {}", path.display(), e))
 }
 
 fn open_file(app: &mut App, mut file_name: String) {
-    use std::fs::File;
-    use std::io::Read;
     //use std::env::current_dir;
     use std::path::Path;
     if file_name.is_empty() { file_name = app.file_name.clone(); };
@@ -591,29 +1385,189 @@ fn open_file(app: &mut App, mut file_name: String) {
         .to_str()
         .unwrap()
         .to_string();
-    let mut file = File::open(full_path);
-    match file {
-        Ok(ref mut val) => {
-            let mut data = String::new();
-            val.read_to_string(&mut data).unwrap();
-            app.cols = serde_json::from_str::<Vec<Vec<Vec<String>>>>(&data)
-                .unwrap()
-                .into_iter()
-                .map(|col| {
-                    col.into_iter()
-                        .map(|el| el.into_iter().map(Span::from).collect::<Vec<_>>())
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>();
+    match read_project(&full_path) {
+        Ok((project, version)) => {
+            app.project = project;
+            app.audio_params.sample_rate = app.project.settings.sample_rate as usize;
+            app.insert_text = None;
             app.normal_cursor.x = 1;
-            app.normal_cursor.y = 1;
+            app.normal_cursor.y = INIT_ROW as u16;
             app.visual_cursor.x = 1;
-            app.visual_cursor.y = 1;
+            app.visual_cursor.y = INIT_ROW as u16;
             app.insert_cursor.x = 0;
+            app.history = History::default();
+            app.dirty = false;
+            app.count_lines();
             app.command_buf.clear();
+            if version < project::FORMAT_VERSION {
+                app.command_buf = format!("Upgraded {} from format version {}, save to keep it.", app.file_name, version);
+            }
         }
+        Err(e) => {
+            app.command_buf = e;
+        }
+    }
+}
+
+///Project file from the command line, a directory means its project.tr
+fn find_project(file_path: Option<String>) -> Option<std::path::PathBuf> {
+    let file_path = file_path.unwrap_or("project.tr".to_string());
+    match std::path::Path::new(&file_path).canonicalize() {
+        Ok(value) if value.is_file() => Some(value),
+        Ok(value) => Some(value.join("project.tr")),
         Err(_) => {
-            app.command_buf = format!("Can't find file {}.", file_name);
+            eprintln!("Can't find file {}.", file_path);
+            None
+        }
+    }
+}
+
+fn export_project(project: &Project, format: ExportFormat, path: &std::path::Path) -> std::result::Result<String, String> {
+    let (result, reason) = match format {
+        ExportFormat::Midi => (midi::export(project, path), "out of MIDI range"),
+        ExportFormat::Dawproject => (dawproject::export(project, path), "out of MIDI range"),
+        ExportFormat::Sunvox => (sunvox::export(project, path), "not rendered to samples"),
+    };
+    result.map(|(written, skipped)| {
+        let skipped = if skipped > 0 { format!(", skipped {} {}", skipped, reason) } else { String::new() };
+        format!("Exported {} notes to {}{}", written, path.display(), skipped)
+    })
+}
+
+fn export_file(app: &mut App, format: ExportFormat, file_name: String) {
+    let new_file_name = if file_name.is_empty() { format!("{}.{}", app.file_name, format.extension()) } else { file_name };
+    app.command_buf = match export_project(&app.project, format, std::path::Path::new(&new_file_name)) {
+        Ok(message) | Err(message) => message,
+    };
+}
+
+///Appends the tracks of a MIDI file as new columns
+fn import_midi(app: &mut App, file_name: String) {
+    app.command_buf = match midi::import(std::path::Path::new(&file_name)) {
+        Ok((tracks, _)) if tracks.is_empty() => format!("No notes in {}.", file_name),
+        Ok((tracks, tempo)) => {
+            app.record();
+            let count = tracks.len();
+            app.project.tracks.extend(tracks);
+            if let Some(tempo) = tempo {
+                app.project.settings.tempo = tempo;
+            }
+            app.count_lines();
+            format!("Imported {} tracks from {}.", count, file_name)
+        }
+        Err(e) => e,
+    };
+}
+
+fn export_headless(format: ExportFormat, file_path: Option<String>, output_path: Option<String>) -> i32 {
+    use std::path::{absolute, Path};
+    let Some(full_path) = find_project(file_path) else { return 1 };
+    let out_path = match output_path {
+        Some(path) => absolute(Path::new(&path)).unwrap_or(Path::new(&path).to_path_buf()),
+        None => {
+            let file_name = full_path.file_name().unwrap_or_default().to_str().unwrap_or_default();
+            full_path.with_file_name(format!("{}.{}", file_name, format.extension()))
+        }
+    };
+    let result = read_project(&full_path).and_then(|(project, _)| export_project(&project, format, &out_path));
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn send_headless(file_path: Option<String>, address: String, protocol: pd::Protocol) -> i32 {
+    let Some(full_path) = find_project(file_path) else { return 1 };
+    let cancel = AtomicBool::new(false);
+    let result = read_project(&full_path).and_then(|(project, _)| pd::send(&project, &address, protocol, &cancel));
+    match result {
+        Ok(sent) => {
+            println!("Sent {} notes to {}", sent, address);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+///With `stems` writes one file per track to the directory instead, the flag adds the tracks before their effects
+///Normalization from `off`, `peak` or `lufs` and the target, which defaults to -1 dBFS or -14 LUFS
+fn parse_normalize(kind: &str, target: Option<f32>) -> Option<Normalize> {
+    match kind {
+        "off" => Some(Normalize::Off),
+        "peak" => Some(Normalize::Peak(target.unwrap_or(-1.0))),
+        "lufs" => Some(Normalize::Lufs(target.unwrap_or(-14.0))),
+        _ => None,
+    }
+}
+
+fn render_headless(
+    file_path: Option<String>,
+    output_path: Option<String>,
+    (sample_rate, bits, normalize): (Option<u32>, Option<u32>, Option<Normalize>),
+    stems: Option<(String, bool)>,
+) -> i32 {
+    use std::path::{absolute, Path};
+    let Some(full_path) = find_project(file_path) else { return 1 };
+    let file_name = full_path
+        .file_name()
+        .unwrap_or_default()
+        .to_str()
+        .unwrap_or_default()
+        .to_string();
+    let out_path = match output_path {
+        Some(path) => absolute(Path::new(&path)).unwrap_or(Path::new(&path).to_path_buf()),
+        None => full_path.with_file_name(file_name.clone() + ".wav"),
+    };
+    let stems = stems.map(|(dir, dry)| (absolute(Path::new(&dir)).unwrap_or(Path::new(&dir).to_path_buf()), dry));
+    let mut project = match read_project(&full_path) {
+        Ok((project, _)) => project,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    if let Some(rate) = sample_rate.filter(|it| *it > 0) {
+        project.settings.sample_rate = rate;
+    }
+    if let Some(bits) = bits {
+        project.settings.bit_depth = bits;
+    }
+    if let Some(normalize) = normalize {
+        project.settings.normalize = normalize;
+    }
+    let _ = std::env::set_current_dir(full_path.parent().unwrap_or(Path::new("/")));
+    // Padded so a shorter stage covers the longer one before it.
+    let progress = |stage: RenderStage| eprint!("\r{:<40}", stage.to_string());
+    let cancel = AtomicBool::new(false);
+    let result = load_engine(&project, &file_name, &progress, &cancel).and_then(|(mut engine, log)| {
+        eprint!("\n{}", log);
+        match &stems {
+            Some((dir, dry)) => {
+                let names = project.tracks.iter().map(|it| it.name.clone()).collect::<Vec<_>>();
+                write_stems(&mut engine, &names, dir, *dry, &project.settings, &progress, &cancel)
+            }
+            None => write_audio(&mut engine, &out_path, &project.settings, &tags(&project, &file_name), &progress, &cancel),
+        }
+    });
+    eprintln!();
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            0
+        }
+        Err(log) => {
+            eprintln!("{}", log.trim_end());
+            eprintln!("Render of {} failed.", full_path.display());
+            1
         }
     }
 }
@@ -638,25 +1592,91 @@ fn save_file(app: &mut App, mut file_name: String) {
         .to_string();
     let full_path = std::path::Path::new(&std::env::current_dir().unwrap().to_str().unwrap_or("/"))
         .join(&file_name);
-    let file_cloned = &app
-        .cols
-        .clone()
-        .into_iter()
-        .map(|col| {
-            col.into_iter()
-                .map(|el| el.into_iter().map(|c| c.content).collect::<Vec<_>>())
-                .collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
+    let file_cloned = app.project.to_json().unwrap();
     let file = File::create(full_path).unwrap();
     let mut buf_writer = BufWriter::new(file);
-    serde_json::to_writer(&mut buf_writer, &file_cloned).unwrap();
+    buf_writer.write_all(file_cloned.as_bytes()).unwrap();
     buf_writer.flush().unwrap();
+    app.dirty = false;
 }
 
 #[inline]
 fn rename_track(app: &mut App, name: String) {
-    app.cols[app.normal_cursor.x as usize][0][0] = Span::from(name);
+    app.record();
+    app.track_mut().name = name;
+}
+
+///Adds a route from or to the current track, or replaces the one between the same tracks, unless it closes a cycle
+fn add_route(app: &mut App, route: project::Route) {
+    let mut project = app.project.clone();
+    project.routes.retain(|it| match (it, route) {
+        //A track goes to one bus only
+        (project::Route::Output { from, .. }, project::Route::Output { from: new, .. }) => *from != new,
+        (it, route) => std::mem::discriminant(it) != std::mem::discriminant(&route) || it.edge() != route.edge(),
+    });
+    project.routes.push(route);
+    app.command_buf = match routing::order(&project) {
+        Ok(_) => {
+            app.record();
+            app.project.routes = project.routes;
+            format!("Routed {}.", routing::describe(&app.project, &route))
+        }
+        Err(e) => e,
+    };
+}
+
+///Lists, adds, tunes or trims project samples
+fn sample_command(app: &mut App, args: Vec<String>) {
+    let index = |arg: Option<&String>| arg.and_then(|it| it.parse::<usize>().ok()).filter(|it| *it < app.project.samples.len());
+    let seconds = |arg: Option<&String>| arg.and_then(|it| it.parse::<f32>().ok()).filter(|it| *it >= 0.0);
+    app.command_buf = match args.first().map(|it| it.as_str()) {
+        None if app.project.samples.is_empty() => "No samples, add one with :sample path.wav.".to_string(),
+        None => app
+            .project
+            .samples
+            .iter()
+            .enumerate()
+            .map(|(i, it)| {
+                let trim = match (it.start, it.end) {
+                    (start, end) if end > start => format!(" {}-{} s", start, end),
+                    (start, _) if start > 0.0 => format!(" from {} s", start),
+                    _ => String::new(),
+                };
+                format!("{}: {} at {} Hz{}", i, it.path, it.root, trim)
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+        Some("root") => match (index(args.get(1)), args.get(2).and_then(|it| it.parse::<f32>().ok())) {
+            (Some(i), Some(root)) if root > 0.0 => {
+                app.record();
+                app.project.samples[i].root = root;
+                format!("Sample {} plays at its own speed at {} Hz.", i, root)
+            }
+            _ => "Set the root frequency with :sample root <number> <Hz>.".to_string(),
+        },
+        Some("trim") => match (index(args.get(1)), seconds(args.get(2)), args.get(3).map(|it| seconds(Some(it)))) {
+            (Some(i), Some(start), end) if end != Some(None) => {
+                app.record();
+                let sample = &mut app.project.samples[i];
+                sample.start = start;
+                sample.end = end.flatten().unwrap_or(0.0);
+                format!("Sample {} trimmed.", i)
+            }
+            _ => "Trim a sample with :sample trim <number> <start> [end] in seconds.".to_string(),
+        },
+        Some(_) => {
+            let path = args.join(" ");
+            match wav::read(std::path::Path::new(&path)) {
+                Ok(_) => {
+                    app.record();
+                    app.project.samples.push(project::Sample { path, ..Default::default() });
+                    let i = app.project.samples.len() - 1;
+                    format!("Added sample {}, play it with instrument {}.", i, instruments::SAMPLES as usize + i)
+                }
+                Err(e) => e,
+            }
+        }
+    };
 }
 
 fn exec_command(app: &mut App) {
@@ -698,9 +1718,135 @@ fn exec_command(app: &mut App) {
         "render" => {
             render_and_save_file(app, splitted_commands[1..].join(" "));
         }
+        "export" => {
+            let path = splitted_commands.get(2..).unwrap_or_default().join(" ");
+            match splitted_commands.get(1).and_then(|it| ExportFormat::from_str(it, true).ok()) {
+                Some(format) => export_file(app, format, path),
+                None => {
+                    let formats = ExportFormat::value_variants()
+                        .iter()
+                        .filter_map(|it| it.to_possible_value())
+                        .map(|it| it.get_name().to_string())
+                        .collect::<Vec<_>>();
+                    app.command_buf = format!("Export to {} with :export <format> [file].", formats.join(", "));
+                }
+            }
+        }
+        "pd" => {
+            let protocol = splitted_commands.get(1).and_then(|it| pd::Protocol::from_str(it, true).ok());
+            let address = splitted_commands.get(if protocol.is_some() { 2 } else { 1 }).unwrap_or(&pd::DEFAULT_ADDRESS);
+            start_send(app, address.to_string(), protocol.unwrap_or(pd::Protocol::Fudi));
+        }
+        "import" => {
+            let path = splitted_commands.get(2..).unwrap_or_default().join(" ");
+            match splitted_commands.get(1) {
+                Some(&"midi") if !path.is_empty() => import_midi(app, path),
+                _ => app.command_buf = "Import with :import midi <file>.".to_string(),
+            }
+        }
+        "sr" | "samplerate" => {
+            match splitted_commands.get(1).map(|it| it.parse::<u32>()) {
+                Some(Ok(rate)) if rate > 0 => {
+                    app.record();
+                    app.project.settings.sample_rate = rate;
+                    app.audio_params.sample_rate = rate as usize;
+                    app.command_buf = format!("Sample rate set to {}.", rate);
+                }
+                Some(_) => app.command_buf = format!("Wrong sample rate {}.", splitted_commands[1]),
+                None => app.command_buf = format!("Sample rate is {}.", app.project.settings.sample_rate),
+            }
+        }
+        "bits" => match splitted_commands.get(1).map(|it| it.parse::<u32>()) {
+            Some(Ok(bits)) if wav::BIT_DEPTHS.contains(&bits) => {
+                app.record();
+                app.project.settings.bit_depth = bits;
+                app.command_buf = format!("Bit depth set to {}.", bits);
+            }
+            Some(_) => app.command_buf = format!("Wrong bit depth {}, use 16, 24 or 32.", splitted_commands[1]),
+            None => app.command_buf = format!("Bit depth is {}.", app.project.settings.bit_depth),
+        },
+        "normalize" => {
+            let target = splitted_commands.get(2).map(|it| it.parse::<f32>().ok());
+            match (splitted_commands.get(1), target) {
+                (None, _) => app.command_buf = format!("Normalize is {:?}.", app.project.settings.normalize),
+                (Some(_), Some(None)) => app.command_buf = format!("Wrong target {}.", splitted_commands[2]),
+                (Some(kind), target) => match parse_normalize(kind, target.flatten()) {
+                    Some(normalize) => {
+                        app.record();
+                        app.project.settings.normalize = normalize;
+                        app.command_buf = format!("Normalize set to {:?}.", normalize);
+                    }
+                    None => app.command_buf = "Normalize with :normalize off|peak|lufs [target].".to_string(),
+                },
+            }
+        }
+        "sample" => {
+            sample_command(app, splitted_commands[1..].iter().map(|it| it.to_string()).collect());
+        }
         "rename" => {
             rename_track(app, splitted_commands[1..].join(" "));
         }
+        "bus" => {
+            let name = splitted_commands[1..].join(" ");
+            app.record();
+            app.project.tracks.push(Track::bus(if name.is_empty() { "bus".to_string() } else { name }));
+            app.normal_cursor.x = app.project.tracks.len() as u16;
+            app.normal_cursor.y = app.normal_cursor.y.min(INIT_ROW as u16);
+            app.insert_cursor.x = 0;
+            app.count_lines();
+            app.command_buf = format!("Added a bus, route tracks to it with :route {0} or :send {0} [percent].", app.normal_cursor.x);
+        }
+        "sidechain" | "send" | "route" | "unroute" => {
+            let current = app.normal_cursor.x as usize - 1;
+            let column = splitted_commands
+                .get(1)
+                .and_then(|it| it.parse::<usize>().ok())
+                .filter(|it| (1..=app.project.tracks.len()).contains(it) && it - 1 != current)
+                .map(|it| it - 1);
+            let percent = splitted_commands.get(2).map(|it| it.parse::<f32>().ok().filter(|it| *it >= 0.0));
+            match (splitted_commands[0], column, percent) {
+                ("sidechain", Some(key), None) => add_route(app, project::Route::Sidechain { track: current, key }),
+                ("send", Some(to), Some(Some(percent))) => add_route(app, project::Route::Send { from: current, to, percent }),
+                ("send", Some(to), None) => add_route(app, project::Route::Send { from: current, to, percent: 100.0 }),
+                ("route", Some(to), None) => add_route(app, project::Route::Output { from: current, to }),
+                ("unroute", Some(other), None) => {
+                    app.record();
+                    app.project.routes.retain(|it| it.edge() != (current, other) && it.edge() != (other, current));
+                    app.command_buf = format!("Removed the routes between columns {} and {}.", current + 1, other + 1);
+                }
+                ("sidechain", _, _) => app.command_buf = "Key the fx of this track with :sidechain <column>.".to_string(),
+                ("send", _, _) => app.command_buf = "Send this track with :send <column> [percent].".to_string(),
+                ("route", _, _) => app.command_buf = "Route this track to a bus with :route <column>.".to_string(),
+                _ => app.command_buf = "Remove routes with :unroute <column>.".to_string(),
+            }
+        }
+        "routes" => {
+            app.command_buf = if app.project.routes.is_empty() {
+                "No routes, add them with :sidechain, :send or :route.".to_string()
+            } else {
+                app.project.routes.iter().map(|it| routing::describe(&app.project, it)).collect::<Vec<_>>().join(", ")
+            };
+        }
+        "tabnew" => {
+            let path = splitted_commands[1..].join(" ");
+            app.new_tab();
+            if !path.is_empty() {
+                open_file(app, path);
+            }
+        }
+        "tabn" | "tabnext" => {
+            app.next_tab(splitted_commands.get(1).and_then(|it| it.parse().ok()));
+        }
+        "tabp" | "tabprevious" => {
+            app.prev_tab();
+        }
+        "tabc" | "tabclose" if app.dirty => {
+            app.command_buf = "No write since last change, add ! to close anyway.".to_string();
+        }
+        "tabc" | "tabclose" | "tabc!" | "tabclose!" => {
+            app.close_tab();
+            app.command_buf.clear();
+        }
         "s" | "save" => {
             save_file(app, splitted_commands[1..].join(" "));
             app.command_buf.clear();
@@ -762,54 +1908,9 @@ fn start_app(working_file: &str) -> Result<()> {
     enable_raw_mode()?;
     let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
     terminal.clear()?;
-    let mut app = App {
-        normal_cursor: NormalCursor{ x: 1, y: 1},
-        visual_cursor: VisualCursor{ x: 1, y: 1},
-        insert_cursor: InsertCursor::default(),
-        current_mode: Mode::Normal,
-        audio_params: OutputDeviceParameters {
-            channels_count: 2,
-            sample_rate: 44100,
-            channel_sample_count: 4410,
-        },
-        command_buf: String::new(),
-        //file_path: std::env::current_dir().unwrap().to_str().unwrap_or("/").to_string(),
-        file_name: working_file.to_string(),
-        theme: config_file,
-        x_bound: 0,
-        y_bound: 0,
-        current_times: String::new(),
-        cols: vec![
-            vec![vec![Span::from("1").to_owned()]; 3],
-            vec![
-                vec![Span::from("name").to_owned()],
-                vec![
-                    Span::from("440").to_owned(),
-                    Span::from("1").to_owned(),
-                    Span::from("1").to_owned(),
-                ],
-                vec![Span::from("1").to_owned(); 7],
-                vec![Span::from("1").to_owned(); 7],
-            ],
-            vec![
-                vec![Span::from("name").to_owned()],
-                vec![
-                    Span::from("440").to_owned(),
-                    Span::from("1").to_owned(),
-                    Span::from("1").to_owned(),
-                ],
-                vec![Span::from("1").to_owned(); 7],
-            ],
-        ],
-        yank_buf: Vec::new(),
-        //constrains: vec![Constraint::Max(3); 6],
-        help_page: 0,
-        is_help: false,
-        should_leave: false,
-    };
+    let mut app = App::new(working_file, config_file);
     let editor = std::env::var("EDITOR").unwrap_or("nvim".to_string());
     let mut rand_iter = core::iter::repeat_with(|| fastrand::u8(0..=9));
-    let fn_status = String::new();
     let full_path_lib =
         std::path::Path::new(&std::env::current_dir().unwrap().to_str().unwrap_or("/"))
             .join("cargolib/")
@@ -818,13 +1919,13 @@ fn start_app(working_file: &str) -> Result<()> {
    // let full_path_file =
    //     std::path::Path::new(&std::env::current_dir().unwrap().to_str().unwrap_or("/"))
    //         .join("project.tr");
-    app.y_bound = app.cols[app.normal_cursor.x as usize].len() as u16;
+    app.y_bound = app.row_count(app.normal_cursor.x) as u16;
     app.count_lines();
     loop {
         //let table_cols = app.cols.to_owned();
         //table_rows[app.normal_cursor.y as usize][app.normal_cursor.x as usize] = cur_cell_text;
         //let y_bound = core::iter::repeat_with(|| &app.rows.iter().next().unwrap_or(&Vec::<Span>::new()).get(app.normal_cursor.x as usize)).count();
-        let y_bound: u16 = app.cols[app.normal_cursor.x as usize].len() as u16;
+        let y_bound: u16 = app.row_count(app.normal_cursor.x) as u16;
         // for el in &app.rows[app.normal_cursor.x as usize] {
         //     if el.get(app.normal_cursor.x as usize).is_some() {
         //         y_bound += 1;
@@ -844,6 +1945,7 @@ fn start_app(working_file: &str) -> Result<()> {
         terminal.draw(|f| {
             app.x_bound = f.area().width;
             app.y_bound = f.area().height;
+            app.scroll_to_cursor();
             // let size_x = ratatui::layout::Layout::default()
             //     .direction(Direction::Vertical)
             //     .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
@@ -873,6 +1975,15 @@ fn start_app(working_file: &str) -> Result<()> {
                     height: f.area().height - 2,
                 },
             );
+            f.render_widget(
+                app.tab_bar(),
+                layout::Rect {
+                    x: f.area().x + 1,
+                    y: f.area().y,
+                    width: f.area().width.saturating_sub(2),
+                    height: 1,
+                },
+            );
             f.render_widget(TableWithCells { app: &app }, f.area());
             f.render_widget(
                 format!(
@@ -905,7 +2016,7 @@ fn start_app(working_file: &str) -> Result<()> {
                 },
             );
             f.render_widget(
-                fn_status.clone().set_style(Modifier::REVERSED),
+                app.render_status.clone().set_style(Modifier::REVERSED),
                 layout::Rect {
                     x: 0,
                     y: app.y_bound - 3,
@@ -941,7 +2052,13 @@ fn start_app(working_file: &str) -> Result<()> {
                 );
             }
         })?;
+        poll_render(&mut app);
+        if !event::poll(std::time::Duration::from_millis(50))? {
+            continue;
+        }
         let match_event = event::read()?;
+        //Second key of two key commands like zz
+        let pending_key = app.pending_key.take();
         match match_event {
             Event::Key(KeyEvent {
                 code: KeyCode::Char('q'),
@@ -963,13 +2080,7 @@ fn start_app(working_file: &str) -> Result<()> {
                     app.current_times.push(matched_code);
                 }
                 Mode::Insert => {
-                    let temp_span = app.cols[app.normal_cursor.x as usize]
-                        [app.normal_cursor.y as usize][app.insert_cursor.x as usize]
-                        .clone();
-                    app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize]
-                        [app.insert_cursor.x as usize]
-                        .content =
-                        (temp_span.content.to_string() + &matched_code.to_string()).into();
+                    app.edit_field(|text| text.push(matched_code));
                 }
                 Mode::Command => {
                     app.command_buf.push(matched_code);
@@ -981,12 +2092,7 @@ fn start_app(working_file: &str) -> Result<()> {
             }) => match app.current_mode {
                 Mode::Normal | Mode::Visual => {}
                 Mode::Insert => {
-                    let temp_span = app.cols[app.normal_cursor.x as usize]
-                        [app.normal_cursor.y as usize][app.insert_cursor.x as usize]
-                        .clone();
-                    app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize]
-                        [app.insert_cursor.x as usize]
-                        .content = (temp_span.content.to_string() + ".").into();
+                    app.edit_field(|text| text.push('.'));
                 }
                 Mode::Command => {
                     app.command_buf.push('.');
@@ -998,12 +2104,7 @@ fn start_app(working_file: &str) -> Result<()> {
             }) => match app.current_mode {
                 Mode::Normal | Mode::Visual => {}
                 Mode::Insert => {
-                    let temp_span = app.cols[app.normal_cursor.x as usize]
-                        [app.normal_cursor.y as usize][app.insert_cursor.x as usize]
-                        .clone();
-                    app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize]
-                        [app.insert_cursor.x as usize]
-                        .content = (temp_span.content.to_string() + ",").into();
+                    app.edit_field(|text| text.push(','));
                 }
                 Mode::Command => {
                     app.command_buf.push(',');
@@ -1015,20 +2116,38 @@ fn start_app(working_file: &str) -> Result<()> {
             }) => match app.current_mode {
                 Mode::Normal | Mode::Visual => {}
                 Mode::Insert => {
-                    let temp_span = app.cols[app.normal_cursor.x as usize]
-                        [app.normal_cursor.y as usize][app.insert_cursor.x as usize]
-                        .clone();
-                    app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize]
-                        [app.insert_cursor.x as usize]
-                        .content = (temp_span.content.to_string() + "/").into();
+                    app.edit_field(|text| text.push('/'));
                 }
                 Mode::Command => {
                     app.command_buf.push('/');
                 }
             },
+            Event::Key(KeyEvent {
+                modifiers: KeyModifiers::CONTROL,
+                code: code @ (KeyCode::Char('d') | KeyCode::Char('u')),
+                ..
+            }) => match app.current_mode {
+                Mode::Normal | Mode::Visual => app.scroll_half_page(code == KeyCode::Char('d')),
+                Mode::Command | Mode::Insert => {}
+            },
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('z'),
+                ..
+            }) => match app.current_mode {
+                Mode::Normal | Mode::Visual if pending_key == Some('z') => {
+                    app.scroll_y = app.normal_cursor.y.saturating_sub(app.view_height() / 2);
+                }
+                Mode::Normal | Mode::Visual => app.pending_key = Some('z'),
+                Mode::Command => app.command_buf.push('z'),
+                Mode::Insert => {}
+            },
             Event::Key(KeyEvent {
                 code: KeyCode::Esc, ..
             }) => {
+                // Esc first leaves the other modes, only in normal mode it stops playback or a render.
+                if let Mode::Normal = app.current_mode {
+                    cancel_render(&mut app);
+                }
                 app.is_help = false;
                 app.current_mode = Mode::Normal;
                 let _ = &app.command_buf.clear();
@@ -1039,8 +2158,13 @@ fn start_app(working_file: &str) -> Result<()> {
                 code: KeyCode::Char('i'),
                 ..
             }) => match app.current_mode {
+                Mode::Normal | Mode::Visual if app.field_count(app.normal_cursor.x, app.normal_cursor.y) == 0 => {
+                    app.command_buf = "Nothing to edit here, add an fx with t.".to_string();
+                }
                 Mode::Normal | Mode::Visual => {
                     app.current_mode = Mode::Insert;
+                    app.insert_text = None;
+                    app.history.in_insert = false;
                     let _ = &app.current_times.clear();
                 }
                 Mode::Command => {
@@ -1054,12 +2178,8 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => match app.current_mode {
                 Mode::Insert => {
-                    let temp_cell = app.cols[app.normal_cursor.x as usize]
-                        [app.normal_cursor.y as usize][app.insert_cursor.x as usize]
-                        .clone();
-                    app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize]
-                        [app.insert_cursor.x as usize] =
-                        temp_cell.content(format!("{}", rand_iter.next().unwrap()));
+                    let digit = rand_iter.next().unwrap();
+                    app.edit_field(|text| *text = digit.to_string());
                 }
                 Mode::Normal => {}
                 Mode::Command => {
@@ -1077,34 +2197,9 @@ fn start_app(working_file: &str) -> Result<()> {
                     Mode::Normal | Mode::Visual => {
                         //let x_bound = app.rows[app.normal_cursor.y as usize].len() as u16;
                         let final_cursor = app.normal_cursor.x.saturating_sub(count);
-                        if ((app.normal_cursor.y as usize) < app.cols[final_cursor as usize].len()) && (final_cursor > 0) { app.normal_cursor.x = final_cursor };
-                    }
-                    Mode::Insert => {
-                        let new_cursor_insert = app.insert_cursor.x as isize - count as isize;
-                        let insert_bound = app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize].len() as isize;
-                       // let insert_bound = if app.normal_cursor.y == 0 {
-                       //     1
-                       // } else if app.normal_cursor.y == 1 {
-                       //     3
-                       // } else {
-                       //     7
-                       // };
-                        let new_cursor_normal = app.normal_cursor.x as isize
-                            - (((new_cursor_insert - insert_bound + 1) / insert_bound).abs());
-                        if app.normal_cursor.y >= app.cols[new_cursor_normal as usize].len() as u16 {
-                        }
-                        else if new_cursor_normal > 0 {
-                            app.insert_cursor.x = if new_cursor_insert >= 0 {
-                                new_cursor_insert as u16
-                            } else {
-                                (insert_bound + new_cursor_insert % insert_bound) as u16
-                            };
-                            app.normal_cursor.x = new_cursor_normal as u16;
-                        } else {
-                            app.normal_cursor.x = 1;
-                            app.insert_cursor.x = 0;
-                        }
+                        if (final_cursor > 0) && ((app.normal_cursor.y as usize) < app.row_count(final_cursor)) { app.normal_cursor.x = final_cursor };
                     }
+                    Mode::Insert => app.insert_left(count),
                     Mode::Command => {
                         app.command_buf.push('h');
                     }
@@ -1171,32 +2266,16 @@ fn start_app(working_file: &str) -> Result<()> {
                 let _ = &app.current_times.clear();
                 match app.current_mode {
                     Mode::Normal | Mode::Visual => {
-                        let x_bound = app.cols.len() as u16;
+                        let x_bound = app.col_count() as u16;
                         let new_x = app.normal_cursor.x.saturating_add(count);
                         app.normal_cursor.x = if new_x > x_bound - 1 {
                             x_bound - 1
-                        } else if (app.normal_cursor.y as usize) < app.cols[new_x as usize].len() {
+                        } else if (app.normal_cursor.y as usize) < app.row_count(new_x) {
                             new_x
                         }
                             else { app.normal_cursor.x };
                     }
-                    Mode::Insert => {
-                        let insert_bound = app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize].len() as u16;
-                        let new_cursor_insert = app.insert_cursor.x + count as u16;
-                        let new_cursor_normal = app
-                            .normal_cursor
-                            .x
-                            .saturating_add(new_cursor_insert / insert_bound);
-                        if new_cursor_normal < app.cols.len() as u16 && app.normal_cursor.y >= app.cols[new_cursor_normal as usize].len() as u16 {
-                        }
-                        else if new_cursor_normal < app.cols.len() as u16 {
-                            app.insert_cursor.x = (new_cursor_insert) % insert_bound;
-                            app.normal_cursor.x = new_cursor_normal;
-                        } else {
-                            app.insert_cursor.x = insert_bound - 1;
-                            app.normal_cursor.x = app.cols.len() as u16 - 1;
-                        }
-                    }
+                    Mode::Insert => app.insert_right(count),
 
                     Mode::Command => {
                         app.command_buf.push('l');
@@ -1240,10 +2319,14 @@ fn start_app(working_file: &str) -> Result<()> {
                 code: KeyCode::Char('g'),
                 ..
             }) => {
-                let count: u16 = app.current_times.parse().unwrap_or(0);
-                let _ = &app.current_times.clear();
                 match app.current_mode {
+                    //First g waits for gg, gt or gT and keeps the count
+                    Mode::Normal | Mode::Visual if pending_key != Some('g') => {
+                        app.pending_key = Some('g');
+                    }
                     Mode::Normal | Mode::Visual => {
+                        let count: u16 = app.current_times.parse().unwrap_or(0);
+                        let _ = &app.current_times.clear();
                         app.normal_cursor.y = if count < y_bound {
                             count
                         } else {
@@ -1252,7 +2335,7 @@ fn start_app(working_file: &str) -> Result<()> {
                         app.count_lines();
                     }
                     Mode::Command => {
-                        app.command_buf.push('G');
+                        app.command_buf.push('g');
                     }
                     Mode::Insert => {}
                 }
@@ -1264,8 +2347,10 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => {
                 match app.current_mode {
+                    Mode::Insert | Mode::Normal | Mode::Visual if app.track().bus => app.command_buf = BUS_NOTES.to_string(),
                     Mode::Insert | Mode::Normal | Mode::Visual => {
-                        app.cols[app.normal_cursor.x as usize].push(vec![Span::from("1"); 7]);
+                        app.record();
+                        app.track_mut().notes.push(NoteRow::default());
                         app.count_lines();
                     }
                     Mode::Command => {
@@ -1278,8 +2363,15 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => {
                 match app.current_mode {
+                    Mode::Normal | Mode::Visual if pending_key == Some('g') => {
+                        let count = app.current_times.parse().ok();
+                        app.current_times.clear();
+                        app.next_tab(count);
+                    }
                     Mode::Insert | Mode::Normal | Mode::Visual => {
-                        app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize].extend(vec![Span::from("0"), Span::from("0")]);
+                        let y = app.normal_cursor.y as usize;
+                        app.record();
+                        app.track_mut().push_fx(y);
                         app.count_lines();
                     }
                     Mode::Command => {
@@ -1292,16 +2384,17 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => {
                 match app.current_mode {
+                    Mode::Normal | Mode::Visual if pending_key == Some('g') => app.prev_tab(),
                     Mode::Normal | Mode::Visual => {},
                     Mode::Insert => {
-                        if app.insert_cursor.x % 2 == 0 {
-                            app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize].remove(app.insert_cursor.x as usize);
-                            app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize].remove(app.insert_cursor.x as usize - 1 );
+                        let (y, i) = (app.normal_cursor.y as usize, app.insert_cursor.x as usize);
+                        app.record();
+                        app.track_mut().remove_fx(y, i);
+                        let bound = app.field_count(app.normal_cursor.x, app.normal_cursor.y) as u16;
+                        if app.insert_cursor.x >= bound {
+                            app.insert_cursor.x = bound.saturating_sub(1);
                         }
-                        else {
-                            app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize].remove(app.insert_cursor.x as usize + 1);
-                            app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize].remove(app.insert_cursor.x as usize);
-                        };
+                        app.insert_text = None;
                     }
                     Mode::Command => {
                         app.command_buf.push('T');
@@ -1313,11 +2406,8 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => match app.current_mode {
                 Mode::Insert | Mode::Normal | Mode::Visual => {
-                    app.cols.push(vec![
-                        vec![Span::from("name")],
-                        vec![Span::from("440"), Span::from("1"), Span::from("1")],
-                        vec![Span::from("1"); 7],
-                    ]);
+                    app.record();
+                    app.project.tracks.push(Track::default());
                 }
                 Mode::Command => {
                     app.command_buf.push('=');
@@ -1328,8 +2418,12 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => match app.current_mode {
                 Mode::Insert | Mode::Normal => {
-                    app.cols[app.normal_cursor.x as usize].remove(app.normal_cursor.y as usize);
-                    if y_bound - 2 < app.normal_cursor.y {
+                    let y = app.normal_cursor.y as usize;
+                    if y >= NOTE_ROW {
+                        app.record();
+                        app.track_mut().notes.remove(y - NOTE_ROW);
+                    }
+                    if (app.row_count(app.normal_cursor.x) as u16) <= app.normal_cursor.y {
                         app.normal_cursor.y = app.normal_cursor.y.saturating_sub(1);
                     }
                     app.count_lines();
@@ -1338,10 +2432,14 @@ fn start_app(working_file: &str) -> Result<()> {
                 Mode::Visual => {
                     let (min_x, max_x) = minmax_x(&app);
                     let (min_y, max_y) = minmax_y(&app);
-                    for i in min_x..=max_x {
-                        app.cols[i as usize].drain((min_y as usize)..=(max_y as usize));
+                    app.record();
+                    for track in &mut app.project.tracks[(min_x as usize - 1)..=(max_x as usize - 1)] {
+                        let notes_len = track.notes.len();
+                        let start = (min_y as usize).saturating_sub(NOTE_ROW).min(notes_len);
+                        let end = (max_y as usize + 1).saturating_sub(NOTE_ROW).min(notes_len);
+                        track.notes.drain(start..end);
                     }
-                    let new_bound = app.cols[app.normal_cursor.x as usize].len() as u16 - 1;
+                    let new_bound = app.row_count(app.normal_cursor.x) as u16 - 1;
                     if new_bound < app.normal_cursor.y {
                         app.normal_cursor.y = new_bound; 
                     }
@@ -1357,19 +2455,23 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => match app.current_mode {
                 Mode::Insert | Mode::Normal  => {
-                    app.yank_buf = vec![vec![app.cols[app.normal_cursor.x as usize]
-                        [app.normal_cursor.y as usize]
-                        .clone()]];
+                    let y = app.normal_cursor.y as usize;
+                    if y >= NOTE_ROW {
+                        app.yank_buf = vec![vec![app.track().notes[y - NOTE_ROW].clone()]];
+                    }
                     app.current_mode = Mode::Normal;
                 }
                 Mode::Visual => {
                     let (min_x, max_x) = minmax_x(&app);
                     let (min_y, max_y) = minmax_y(&app);
-                    app.yank_buf = app.cols[(min_x as usize)..=(max_x as usize)].to_vec()
+                    app.yank_buf = app.project.tracks[(min_x as usize - 1)..=(max_x as usize - 1)]
                         .iter()
-                        .map(|it|
-                        it[(min_y as usize)..=(max_y as usize)].to_vec()
-                        )
+                        .map(|it| {
+                            let start = (min_y as usize).saturating_sub(NOTE_ROW).min(it.notes.len());
+                            let end = (max_y as usize + 1).saturating_sub(NOTE_ROW).min(it.notes.len());
+                            it.notes[start..end].to_vec()
+                        })
+                        .filter(|it| !it.is_empty())
                     .collect::<Vec<_>>();
                     app.current_mode = Mode::Normal;
                 }
@@ -1382,10 +2484,13 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => {
                 match app.current_mode {
+                    Mode::Insert | Mode::Normal | Mode::Visual if app.track().bus => app.command_buf = BUS_NOTES.to_string(),
                     Mode::Insert | Mode::Normal | Mode::Visual => {
                         if !app.yank_buf.is_empty() {
-                            app.cols[app.normal_cursor.x as usize]
-                                .insert(app.normal_cursor.y as usize + 1, app.yank_buf[0][0].clone());
+                            let y = (app.normal_cursor.y as usize + 1).saturating_sub(NOTE_ROW);
+                            let note = app.yank_buf[0][0].clone();
+                            app.record();
+                            app.track_mut().notes.insert(y, note);
                             app.count_lines();
                         }
                     }
@@ -1406,10 +2511,13 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => {
                 match app.current_mode {
+                    Mode::Insert | Mode::Normal | Mode::Visual if app.track().bus => app.command_buf = BUS_NOTES.to_string(),
                     Mode::Insert | Mode::Normal | Mode::Visual => {
                         if !app.yank_buf.is_empty() {
-                            app.cols[app.normal_cursor.x as usize]
-                                .insert(app.normal_cursor.y as usize, app.yank_buf[0][0].clone());
+                            let y = (app.normal_cursor.y as usize).saturating_sub(NOTE_ROW);
+                            let note = app.yank_buf[0][0].clone();
+                            app.record();
+                            app.track_mut().notes.insert(y, note);
                             app.count_lines();
                         }
                     }
@@ -1429,10 +2537,11 @@ fn start_app(working_file: &str) -> Result<()> {
                 code: KeyCode::Char('-'),
                 ..
             }) => match app.current_mode {
-               Mode::Normal  => {
-                    app.cols.remove(app.normal_cursor.x as usize);
-                    app.normal_cursor.x = if app.cols.len() - 1 < app.normal_cursor.x as usize {
-                        app.cols.len() as u16 - 1
+               Mode::Normal if app.project.tracks.len() > 1 => {
+                    app.record();
+                    app.project.remove_tracks(app.normal_cursor.x as usize - 1..=app.normal_cursor.x as usize - 1);
+                    app.normal_cursor.x = if app.col_count() - 1 < app.normal_cursor.x as usize {
+                        app.col_count() as u16 - 1
                     } else {
                         app.normal_cursor.x
                     };
@@ -1440,27 +2549,42 @@ fn start_app(working_file: &str) -> Result<()> {
                 }
                 Mode::Visual => {
                     let (min_x, max_x) = minmax_x(&app);
-                    app.cols.drain((min_x as usize)..=(max_x as usize));
-                    app.normal_cursor.x = if app.cols.len() - 1 < app.normal_cursor.x as usize {
-                        app.cols.len() as u16 - 1
+                    if max_x - min_x + 1 < app.project.tracks.len() as u16 {
+                        app.record();
+                        app.project.remove_tracks((min_x as usize - 1)..=(max_x as usize - 1));
+                    }
+                    app.normal_cursor.x = if app.col_count() - 1 < app.normal_cursor.x as usize {
+                        app.col_count() as u16 - 1
                     } else {
                         app.normal_cursor.x
                     };
                     app.current_mode = Mode::Normal;
                     app.count_lines();
                 }
+                Mode::Normal => {}
                 Mode::Insert => {
-                    let temp_span = app.cols[app.normal_cursor.x as usize]
-                        [app.normal_cursor.y as usize][app.insert_cursor.x as usize]
-                        .clone();
-                    app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize]
-                        [app.insert_cursor.x as usize]
-                        .content = (temp_span.content.to_string() + "-").into();
+                    app.edit_field(|text| text.push('-'));
                 }
                 Mode::Command => {
                     app.command_buf.push('-');
                 }
             },
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('M'),
+                ..
+            }) => match app.current_mode {
+                Mode::Normal | Mode::Visual => app.toggle_mixer(|it| it.mute = !it.mute),
+                Mode::Command => app.command_buf.push('M'),
+                Mode::Insert => {}
+            },
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('S'),
+                ..
+            }) => match app.current_mode {
+                Mode::Normal | Mode::Visual => app.toggle_mixer(|it| it.solo = !it.solo),
+                Mode::Command => app.command_buf.push('S'),
+                Mode::Insert => {}
+            },
             Event::Key(KeyEvent {
                 code: KeyCode::Char('v'),
                 ..
@@ -1479,18 +2603,9 @@ fn start_app(working_file: &str) -> Result<()> {
                 ..
             }) => match app.current_mode {
                 Mode::Insert => {
-                    let temp_span = app.cols[app.normal_cursor.x as usize]
-                        [app.normal_cursor.y as usize][app.insert_cursor.x as usize]
-                        .clone();
-                    let slice_len = if temp_span.content.is_empty() {
-                        0
-                    } else {
-                        temp_span.content.len() - 1
-                    };
-                    let new_line = &mut temp_span.content.to_string()[..slice_len];
-                    app.cols[app.normal_cursor.x as usize][app.normal_cursor.y as usize]
-                        [app.insert_cursor.x as usize]
-                        .content = (String::from(new_line)).into();
+                    app.edit_field(|text| {
+                        text.pop();
+                    });
                 }
                 Mode::Normal | Mode::Visual => {}
                 Mode::Command => {
@@ -1583,6 +2698,27 @@ fn start_app(working_file: &str) -> Result<()> {
                 //app.insert_cursor.x = 0;
                 open_file(&mut app, "".to_string());
             }
+            Event::Key(KeyEvent {
+                code: KeyCode::Char(' '),
+                ..
+            }) => match app.current_mode {
+                Mode::Normal | Mode::Visual if app.render_job.is_some() => toggle_pause(&mut app),
+                Mode::Normal | Mode::Visual => {
+                    let range = play_range(&app);
+                    start_render(&mut app, RenderAction::Play(range));
+                }
+                Mode::Command => app.command_buf.push(' '),
+                Mode::Insert => {}
+            },
+            Event::Key(KeyEvent {
+                modifiers: KeyModifiers::NONE,
+                code: KeyCode::Char('u'),
+                ..
+            }) => match app.current_mode {
+                Mode::Normal | Mode::Visual => app.undo(),
+                Mode::Command => app.command_buf.push('u'),
+                Mode::Insert => {}
+            },
             Event::Key(KeyEvent {
                 code: KeyCode::Enter,
                 ..
@@ -1604,22 +2740,10 @@ fn start_app(working_file: &str) -> Result<()> {
                 modifiers: KeyModifiers::CONTROL,
                 code: KeyCode::Char('r'),
                 ..
-            }) => {
-                let out_vec = render(&mut app);
-                let max_len = out_vec.len();
-                let mut out_vec_iter = out_vec.into_iter();
-                        std::thread::spawn(move || {
-                            let _aud = run_output_device(app.audio_params, move |data| {
-                                for samples in data {
-                                    *samples = out_vec_iter.next().unwrap_or(0.0);
-                                }
-                            })
-                            .unwrap();
-                            std::thread::sleep(std::time::Duration::from_secs(
-                                max_len as u64 / 44100,
-                            ));
-                        });
-                    }
+            }) => match app.current_mode {
+                Mode::Normal | Mode::Visual => app.redo(),
+                Mode::Command | Mode::Insert => {}
+            },
             _ => (),
         }
         if app.should_leave {
@@ -1632,6 +2756,21 @@ fn start_app(working_file: &str) -> Result<()> {
 }
 fn main() {
     let cli = Cli::parse();
+    match cli.command {
+        Some(Commands::Render { file_path, output_path, sample_rate, bits, normalize, target, stems, dry }) => {
+            let bits = bits.and_then(|it| it.parse().ok());
+            let normalize = normalize.and_then(|it| parse_normalize(&it, target));
+            let stems = stems.map(|dir| (dir, dry));
+            std::process::exit(render_headless(file_path.or(cli.path), output_path, (sample_rate, bits, normalize), stems));
+        }
+        Some(Commands::Export { format, file_path, output_path }) => {
+            std::process::exit(export_headless(format, file_path.or(cli.path), output_path));
+        }
+        Some(Commands::Pd { file_path, address, protocol }) => {
+            std::process::exit(send_headless(file_path.or(cli.path), address, protocol));
+        }
+        None => {}
+    }
 
     use std::path::Path;
     let mut working_file: &str = "project.tr";
@@ -1651,3 +2790,62 @@ fn main() {
 
     let _ = start_app(working_file);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_cursor_crosses_bus_init_row() {
+        let mut app = App::new("project.tr", HashMap::new());
+        app.project.tracks = vec![Track::default(), Track::bus("bus".to_string()), Track::default()];
+        app.current_mode = Mode::Insert;
+        app.insert_cursor.x = 2;
+        assert_eq!(app.field_count(2, INIT_ROW as u16), 0);
+        app.insert_right(1);
+        assert_eq!((app.normal_cursor.x, app.insert_cursor.x), (2, 0));
+        app.insert_right(1);
+        assert_eq!((app.normal_cursor.x, app.insert_cursor.x), (3, 0));
+        app.insert_left(1);
+        assert_eq!((app.normal_cursor.x, app.insert_cursor.x), (2, 0));
+        app.insert_left(1);
+        assert_eq!((app.normal_cursor.x, app.insert_cursor.x), (1, 0));
+    }
+
+    #[test]
+    fn reports_every_render_stage() {
+        let project = Project { tracks: vec![Track::default(); 3], ..Project::default() };
+        let mut engine = engine::Engine::new(&project, None, Vec::new()).unwrap();
+        let stages = std::cell::RefCell::new(Vec::new());
+        let progress = |stage| {
+            stages.borrow_mut().push(match stage {
+                RenderStage::Rendering(track, tracks, _) => Some((track, tracks)),
+                RenderStage::Mixing(_) => None,
+                _ => panic!("run_engine only renders and mixes"),
+            })
+        };
+        run_engine(&mut engine, &progress, &AtomicBool::new(false), |_, _| Ok(())).unwrap();
+        let stages = stages.into_inner();
+        for track in 1..=3 {
+            assert!(stages.contains(&Some((track, 3))));
+        }
+        assert!(stages.contains(&None));
+    }
+
+    #[test]
+    fn normalizes_each_stem() {
+        let project = Project { tracks: vec![Track::default()], ..Project::default() };
+        let dir = std::env::temp_dir().join(format!("iaue-stems-{}", std::process::id()));
+        let stems = |normalize| {
+            let settings = Settings { normalize, ..Settings::default() };
+            let mut engine = engine::Engine::new(&project, None, Vec::new()).unwrap();
+            write_stems(&mut engine, &["lead".to_string()], &dir, false, &settings, &|_| {}, &AtomicBool::new(false)).unwrap()
+        };
+        assert_eq!(stems(Normalize::Peak(-6.0)), format!("Saved 1 stems to {}", dir.display()));
+        let (_, frames) = wav::read(&dir.join("lead.wav")).unwrap();
+        let peak = frames.iter().map(|it| it.left.abs()).fold(0.0, f32::max);
+        assert!((peak - 0.5).abs() < 0.01);
+        assert!(stems(Normalize::Peak(6.0)).contains("samples clip in lead.wav"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
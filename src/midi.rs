@@ -0,0 +1,471 @@
+use crate::engine;
+use crate::instruments;
+use crate::project::{InitRow, NoteFx, NoteRow, Project, Ratio, Track};
+use std::path::Path;
+
+const TICKS_PER_BEAT: u16 = 960;
+// Semitones of a full pitch bend, set on every channel so the bends don't depend on the synth default.
+const BEND_RANGE: f32 = 2.0;
+const BEND_CENTER: u16 = 8192;
+// Channel 10 is for drums in General MIDI.
+const CHANNELS: [u8; 15] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15];
+
+/// Nearest MIDI note of `freq` and the rest in semitones, A4 = 440 Hz is note 69.
+pub fn note_of(freq: f32) -> (i32, f32) {
+    let pitch = 69.0 + 12.0 * (freq / 440.0).log2();
+    let note = pitch.round();
+    (note as i32, pitch - note)
+}
+
+/// A voice that sounds, on the nearest MIDI key. Start and length are in frames of the project sample rate.
+pub struct KeyNote {
+    pub start: usize,
+    pub frames: usize,
+    pub key: u8,
+    // Semitones from the key to the real frequency, -0.5 to 0.5.
+    pub offset: f32,
+    pub vel: f32,
+}
+
+/// Notes of `track` sorted by start, also returns how many were skipped for being out of the MIDI range.
+pub fn key_notes(project: &Project, track: usize) -> (Vec<KeyNote>, usize) {
+    let sample_rate = project.settings.sample_rate as usize;
+    let mut voices = engine::schedule(project, track, sample_rate);
+    voices.sort_by_key(|it| it.start);
+    let mut skipped = 0;
+    let notes = voices
+        .into_iter()
+        .filter_map(|voice| {
+            let frames = voice.frames.min((voice.len.max(0.0) * sample_rate as f32) as usize);
+            if voice.vel <= 0.0 || voice.freq <= 0.0 || !voice.freq.is_finite() || frames == 0 {
+                return None;
+            }
+            let (note, offset) = note_of(voice.freq);
+            if !(0..128).contains(&note) {
+                skipped += 1;
+                return None;
+            }
+            Some(KeyNote { start: voice.start, frames, key: note as u8, offset, vel: voice.vel })
+        })
+        .collect();
+    (notes, skipped)
+}
+
+struct Event {
+    tick: u64,
+    // Note offs go first on the same tick, then bends, then note ons.
+    order: u8,
+    data: Vec<u8>,
+}
+
+// Notes that sound at the same time get their own channel when their pitch bends differ, like MPE does.
+struct Channel {
+    number: u8,
+    bend: Option<u16>,
+    // End tick and key of the notes still sounding.
+    active: Vec<(u64, u8)>,
+}
+
+/// Writes the notes of every track as a Standard MIDI File, one MIDI track per column.
+/// Returns the number of notes written and the number skipped for being out of the MIDI range.
+pub fn export(project: &Project, path: &Path) -> Result<(usize, usize), String> {
+    let sample_rate = project.settings.sample_rate as usize;
+    let ticks_per_second = project.settings.tempo as f64 / 60.0 * TICKS_PER_BEAT as f64;
+    let to_tick = |frame: usize| (frame as f64 / sample_rate as f64 * ticks_per_second).round() as u64;
+    let (mut written, mut skipped) = (0, 0);
+
+    let micros_per_beat = (60_000_000.0 / project.settings.tempo.max(1.0)) as u32;
+    let mut tempo = vec![Event { tick: 0, order: 0, data: meta(0x51, &micros_per_beat.to_be_bytes()[1..]) }];
+    if !project.metadata.title.is_empty() {
+        tempo.push(Event { tick: 0, order: 0, data: meta(0x03, project.metadata.title.as_bytes()) });
+    }
+    let mut chunks = vec![track_chunk(tempo)];
+
+    for (i, track) in project.tracks.iter().enumerate() {
+        let (notes, out_of_range) = key_notes(project, i);
+        skipped += out_of_range;
+        let mut channels = CHANNELS.map(|number| Channel { number, bend: None, active: Vec::new() });
+        let mut events = vec![Event { tick: 0, order: 0, data: meta(0x03, track.name.as_bytes()) }];
+        for note in notes {
+            let (start, end) = (to_tick(note.start), to_tick(note.start + note.frames));
+            if end <= start {
+                continue;
+            }
+            let key = note.key;
+            let bend = (BEND_CENTER as f32 * (1.0 + note.offset / BEND_RANGE)).round().clamp(0.0, 16383.0) as u16;
+            let velocity = ((note.vel * 127.0).round() as u8).clamp(1, 127);
+            for channel in &mut channels {
+                channel.active.retain(|&(until, _)| until > start);
+            }
+            let channel = pick_channel(&mut channels, bend, key);
+            let status = channel.number;
+            if channel.bend.is_none() {
+                events.extend(bend_range(status));
+            }
+            if channel.bend != Some(bend) {
+                events.push(Event { tick: start, order: 1, data: vec![0xE0 | status, (bend & 0x7F) as u8, (bend >> 7) as u8] });
+                channel.bend = Some(bend);
+            }
+            channel.active.push((end, key));
+            events.push(Event { tick: start, order: 2, data: vec![0x90 | status, key, velocity] });
+            events.push(Event { tick: end, order: 0, data: vec![0x80 | status, key, 0] });
+            written += 1;
+        }
+        chunks.push(track_chunk(events));
+    }
+
+    let mut data = b"MThd".to_vec();
+    data.extend(6u32.to_be_bytes());
+    // Format 1, the first track only holds the tempo.
+    data.extend(1u16.to_be_bytes());
+    data.extend((chunks.len() as u16).to_be_bytes());
+    data.extend(TICKS_PER_BEAT.to_be_bytes());
+    for chunk in chunks {
+        data.extend(chunk);
+    }
+    std::fs::write(path, data).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+    Ok((written, skipped))
+}
+
+// Prefers a channel already bent the same way, then an idle one, then the one whose notes end first.
+fn pick_channel(channels: &mut [Channel], bend: u16, key: u8) -> &mut Channel {
+    let index = channels
+        .iter()
+        .position(|it| it.bend == Some(bend) && it.active.iter().all(|&(_, other)| other != key))
+        .or_else(|| channels.iter().position(|it| it.active.is_empty()))
+        .unwrap_or_else(|| {
+            (0..channels.len())
+                .min_by_key(|&i| channels[i].active.iter().map(|&(until, _)| until).max())
+                .unwrap_or(0)
+        });
+    &mut channels[index]
+}
+
+// RPN 0 sets the pitch bend range of a channel.
+fn bend_range(channel: u8) -> Vec<Event> {
+    let semitones = BEND_RANGE as u8;
+    [(101, 0), (100, 0), (6, semitones), (38, 0), (101, 127), (100, 127)]
+        .into_iter()
+        .map(|(controller, value)| Event { tick: 0, order: 0, data: vec![0xB0 | channel, controller, value] })
+        .collect()
+}
+
+fn meta(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut data = vec![0xFF, kind];
+    push_varlen(&mut data, body.len() as u64);
+    data.extend_from_slice(body);
+    data
+}
+
+fn push_varlen(data: &mut Vec<u8>, value: u64) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    data.extend(bytes.iter().rev());
+}
+
+fn track_chunk(mut events: Vec<Event>) -> Vec<u8> {
+    // Stable, so setup messages stay in front of the notes.
+    events.sort_by_key(|it| (it.tick, it.order));
+    let mut body = Vec::new();
+    let mut tick = 0;
+    for event in events {
+        push_varlen(&mut body, event.tick - tick);
+        body.extend(event.data);
+        tick = event.tick;
+    }
+    push_varlen(&mut body, 0);
+    body.extend(meta(0x2F, &[]));
+    let mut chunk = b"MTrk".to_vec();
+    chunk.extend((body.len() as u32).to_be_bytes());
+    chunk.extend(body);
+    chunk
+}
+
+// Frequency ratios of equal tempered notes aren't fractions, they are approximated to about 0.2 cents.
+const TOLERANCE: f64 = 1e-4;
+const MAX_DENOMINATOR: f64 = 10_000.0;
+
+struct MidiNote {
+    start: u64,
+    end: u64,
+    freq: f32,
+    vel: f32,
+}
+
+/// Reads every MIDI track with notes into a track column and returns them with the first tempo in BPM.
+/// The first note goes to the init row and every note row holds ratios to the note before it, like `render` expects.
+/// Notes starting together are layered with note fx 1, gaps become silent rows that keep the previous values (note fx 7).
+pub fn import(path: &Path) -> Result<(Vec<Track>, Option<f32>), String> {
+    let data = std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let invalid = || format!("{} is not a valid MIDI file.", path.display());
+    if data.len() < 14 || &data[..4] != b"MThd" {
+        return Err(invalid());
+    }
+    let division = u16::from_be_bytes([data[12], data[13]]);
+    if division & 0x8000 != 0 {
+        return Err(format!("{} uses SMPTE time, only ticks per beat are supported.", path.display()));
+    }
+    let mut pos = 8 + u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let mut tempos = Vec::new();
+    let mut parsed = Vec::new();
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        let body = data.get(pos + 8..pos + 8 + len).ok_or_else(invalid)?;
+        if &data[pos..pos + 4] == b"MTrk" {
+            parsed.push(read_track(body, &mut tempos).ok_or_else(invalid)?);
+        }
+        pos += 8 + len;
+    }
+    tempos.sort_by_key(|&(tick, _)| tick);
+    // Seconds from the start of the song at `tick`.
+    let seconds = |tick: u64| {
+        let (mut time, mut last_tick, mut micros) = (0.0, 0, 500_000.0);
+        for &(change, value) in tempos.iter().take_while(|&&(change, _)| change < tick) {
+            time += (change - last_tick) as f64 * micros / division as f64 / 1e6;
+            (last_tick, micros) = (change, value as f64);
+        }
+        time + (tick - last_tick) as f64 * micros / division as f64 / 1e6
+    };
+    let tracks = parsed
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (_, notes))| !notes.is_empty())
+        .map(|(i, (name, notes))| to_track(name.unwrap_or_else(|| format!("track {}", i)), notes, &seconds))
+        .collect();
+    let tempo = tempos.first().map(|&(_, micros)| 60_000_000.0 / micros as f32);
+    Ok((tracks, tempo))
+}
+
+// Name and notes of a track chunk, tempo changes are collected for the whole file.
+fn read_track(body: &[u8], tempos: &mut Vec<(u64, u32)>) -> Option<(Option<String>, Vec<MidiNote>)> {
+    let mut name = None;
+    let mut notes = Vec::new();
+    // Sounding notes by channel and key as start, velocity and pitch.
+    let mut sounding: Vec<(u8, u8, u64, u8, f32)> = Vec::new();
+    let mut bends = [0.0f32; 16];
+    let mut ranges = [2.0f32; 16];
+    // Selected RPN of every channel, 0 is the pitch bend range.
+    let mut rpns = [(127u8, 127u8); 16];
+    let (mut pos, mut tick, mut running) = (0, 0u64, 0u8);
+    while pos < body.len() {
+        tick += read_varlen(body, &mut pos)?;
+        let mut status = *body.get(pos)?;
+        if status < 0x80 {
+            status = running;
+        } else {
+            pos += 1;
+        }
+        match status {
+            0xFF => {
+                let kind = *body.get(pos)?;
+                pos += 1;
+                let len = read_varlen(body, &mut pos)? as usize;
+                let value = body.get(pos..pos + len)?;
+                match kind {
+                    0x03 if name.is_none() => name = Some(String::from_utf8_lossy(value).into_owned()),
+                    0x51 if len == 3 => tempos.push((tick, u32::from_be_bytes([0, value[0], value[1], value[2]]))),
+                    _ => {}
+                }
+                pos += len;
+            }
+            0xF0 | 0xF7 => {
+                let len = read_varlen(body, &mut pos)? as usize;
+                pos += len;
+            }
+            0x80..=0xEF => {
+                running = status;
+                let channel = (status & 0x0F) as usize;
+                let size = if matches!(status >> 4, 0xC | 0xD) { 1 } else { 2 };
+                let args = body.get(pos..pos + size)?;
+                pos += size;
+                match (status >> 4, args) {
+                    (0x9, &[key, velocity]) if velocity > 0 => {
+                        let pitch = key as f32 + bends[channel] * ranges[channel];
+                        sounding.push((channel as u8, key, tick, velocity, 440.0 * 2f32.powf((pitch - 69.0) / 12.0)));
+                    }
+                    (0x8 | 0x9, &[key, _]) => {
+                        if let Some(i) = sounding.iter().position(|it| it.0 == channel as u8 && it.1 == key) {
+                            let (_, _, start, velocity, freq) = sounding.remove(i);
+                            notes.push(MidiNote { start, end: tick, freq, vel: velocity as f32 / 127.0 });
+                        }
+                    }
+                    (0xB, &[101, value]) => rpns[channel].0 = value,
+                    (0xB, &[100, value]) => rpns[channel].1 = value,
+                    (0xB, &[6, value]) if rpns[channel] == (0, 0) => ranges[channel] = value as f32,
+                    (0xE, &[lsb, msb]) => {
+                        bends[channel] = (((msb as u16) << 7 | lsb as u16) as f32 - BEND_CENTER as f32) / BEND_CENTER as f32;
+                    }
+                    _ => {}
+                }
+            }
+            _ => return None,
+        }
+    }
+    // Notes without a note off last until the end of the track.
+    for (_, _, start, velocity, freq) in sounding {
+        notes.push(MidiNote { start, end: tick, freq, vel: velocity as f32 / 127.0 });
+    }
+    // Notes that end on the tick they start don't sound, as rows without length every ratio after them would be lost.
+    notes.retain(|it| it.end > it.start);
+    notes.sort_by(|a, b| a.start.cmp(&b.start).then(a.freq.total_cmp(&b.freq)));
+    Some((name, notes))
+}
+
+fn read_varlen(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..4 {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value = value << 7 | (byte & 0x7F) as u64;
+        if byte < 0x80 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn to_track(name: String, notes: Vec<MidiNote>, seconds: &impl Fn(u64) -> f64) -> Track {
+    let mut chords: Vec<Vec<MidiNote>> = Vec::new();
+    for note in notes {
+        match chords.last_mut() {
+            Some(chord) if chord[0].start == note.start => chord.push(note),
+            _ => chords.push(vec![note]),
+        }
+    }
+    let starts = chords.iter().map(|it| it[0].start).collect::<Vec<_>>();
+    // A chord lasts until its longest note ends or the next chord starts.
+    let length = |i: usize| {
+        let end = chords[i].iter().map(|it| it.end).max().unwrap_or(starts[i]);
+        let end = starts.get(i + 1).map_or(end, |&next| end.min(next));
+        seconds(end) - seconds(starts[i])
+    };
+    let first = &chords[0][0];
+    let init = InitRow { freq: first.freq, len: length(0) as f32, vel: first.vel, fx: Vec::new() };
+    // Values the engine reaches after every row, kept in f32 so rounding doesn't add up.
+    let (mut fs, mut ls, mut vs) = (init.freq, init.len, init.vel);
+    let mut time = 0.0;
+    let mut rows = Vec::new();
+    for (i, chord) in chords.iter().enumerate() {
+        let gap = seconds(starts[i]) - time;
+        if gap > 1e-4 {
+            let len = fraction(gap / ls as f64);
+            rows.push(NoteRow {
+                freq: Ratio::default(),
+                len,
+                vel: Ratio::new(0.0, 1.0),
+                instrument: instruments::SINE,
+                fx: vec![NoteFx { code: 7, args: vec![Ratio::new(0.0, 1.0)] }],
+            });
+            time += (ls * len.value()) as f64;
+        }
+        let main = &chord[0];
+        let (freq, len, vel) = (
+            fraction(main.freq as f64 / fs as f64),
+            fraction(length(i) / ls as f64),
+            fraction(main.vel as f64 / vs as f64),
+        );
+        (fs, ls, vs) = (fs * freq.value(), ls * len.value(), vel.value() * vs);
+        time += ls as f64;
+        let fx = chord[1..]
+            .iter()
+            .map(|it| NoteFx {
+                code: 1,
+                args: vec![fraction(it.freq as f64 / fs as f64), fraction(it.vel as f64 / vs as f64)],
+            })
+            .collect();
+        rows.push(NoteRow { freq, len, vel, instrument: instruments::SINE, fx });
+    }
+    Track { name, init, notes: rows, ..Track::default() }
+}
+
+// Closest fraction from the continued fraction of `value` that is within the tolerance.
+fn fraction(value: f64) -> Ratio {
+    if !value.is_finite() || value <= 0.0 {
+        return Ratio::new(0.0, 1.0);
+    }
+    let (mut num, mut den) = (value.floor(), 1.0);
+    let (mut prev_num, mut prev_den) = (1.0, 0.0);
+    let mut rest = value - value.floor();
+    while (num / den / value - 1.0).abs() > TOLERANCE && rest > 1e-12 {
+        let inverse = 1.0 / rest;
+        let whole = inverse.floor();
+        let (next_num, next_den) = (whole * num + prev_num, whole * den + prev_den);
+        if next_den > MAX_DENOMINATOR {
+            break;
+        }
+        (prev_num, prev_den, num, den) = (num, den, next_num, next_den);
+        rest = inverse - whole;
+    }
+    Ratio::new(num as f32, den as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imports_around_zero_length_chords() {
+        let mut track: Vec<u8> = Vec::new();
+        // A chord that starts and ends at tick 0, then two notes of a beat each after a beat of silence.
+        for event in [
+            &[0x00, 0x90, 60, 100][..],
+            &[0x00, 0x90, 64, 100],
+            &[0x00, 0x80, 60, 0],
+            &[0x00, 0x80, 64, 0],
+            &[0x87, 0x40, 0x90, 69, 100],
+            &[0x87, 0x40, 0x80, 69, 0],
+            &[0x00, 0x90, 71, 100],
+            &[0x87, 0x40, 0x80, 71, 0],
+            &[0x00, 0xFF, 0x2F, 0x00],
+        ] {
+            track.extend(event);
+        }
+        let mut data = b"MThd\0\0\0\x06\0\0\0\x01".to_vec();
+        data.extend(960u16.to_be_bytes());
+        data.extend(b"MTrk");
+        data.extend((track.len() as u32).to_be_bytes());
+        data.extend(track);
+        let path = std::env::temp_dir().join(format!("iaue-midi-{}.mid", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let (tracks, _) = import(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let project = Project { tracks, ..Project::default() };
+        let (notes, skipped) = key_notes(&project, 0);
+        let half = project.settings.sample_rate as usize / 2;
+        assert_eq!(skipped, 0);
+        assert_eq!(notes.iter().map(|it| (it.start, it.frames, it.key)).collect::<Vec<_>>(), [(half, half, 69), (2 * half, half, 71)]);
+    }
+
+    #[test]
+    fn round_trips_microtonal_chords() {
+        // 440 Hz with a layer a just fifth up, 660 Hz is key 76 and 0.0196 semitones.
+        let fifth = NoteFx { code: 1, args: vec![Ratio::new(3.0, 2.0)] };
+        let track = Track { notes: vec![NoteRow { fx: vec![fifth], ..NoteRow::default() }], ..Track::default() };
+        let project = Project { tracks: vec![track], ..Project::default() };
+        let path = std::env::temp_dir().join(format!("iaue-midi-chord-{}.mid", std::process::id()));
+        assert_eq!(export(&project, &path).unwrap(), (2, 0));
+        let data = std::fs::read(&path).unwrap();
+        let (tracks, _) = import(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // Channel of the note on and pitch bend events, a bend of 8272 is 80 and 64 as 7 bit bytes.
+        let channel = |event: &[u8]| data.windows(3).find(|it| it[0] >> 4 == event[0] && it[1..] == event[1..]).map(|it| it[0] & 0x0F);
+        let low = channel(&[0x9, 69, 127]).unwrap();
+        let high = channel(&[0x9, 76, 127]).unwrap();
+        assert_ne!(low, high);
+        assert_eq!(channel(&[0xE, 80, 64]), Some(high));
+
+        let project = Project { tracks, ..Project::default() };
+        let (mut notes, skipped) = key_notes(&project, 0);
+        notes.sort_by_key(|it| it.key);
+        assert_eq!(skipped, 0);
+        assert_eq!(notes.iter().map(|it| it.key).collect::<Vec<_>>(), [69, 76]);
+        assert!(notes[0].offset.abs() < 1e-3);
+        assert!((notes[1].offset - 0.0196).abs() < 1e-3);
+    }
+}
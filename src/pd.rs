@@ -0,0 +1,158 @@
+use crate::engine;
+use crate::project::Project;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Address Pure Data listens on by default, `[netreceive -u 3000]` or `[netreceive -u -b 3000]` with `[oscparse]`.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:3000";
+const OSC_ADDRESS: &str = "/iaue/note";
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Protocol {
+    ///Plain text messages for netreceive
+    Fudi,
+    ///Binary OSC messages for oscparse
+    Osc,
+}
+
+/// A voice as it is sent to Pd, the length is in seconds and the params are the note FX `3` arguments.
+struct NoteEvent {
+    start: usize,
+    track: usize,
+    freq: f32,
+    len: f32,
+    vel: f32,
+    instrument: u32,
+    params: Vec<f32>,
+}
+
+/// Collects the sounding voices of every track, sorted by their start frame.
+fn events(project: &Project) -> Vec<NoteEvent> {
+    let sample_rate = project.settings.sample_rate as usize;
+    let mut events = (0..project.tracks.len())
+        .flat_map(|track| engine::schedule(project, track, sample_rate).into_iter().map(move |voice| (track, voice)))
+        .filter(|(_, voice)| voice.vel > 0.0 && voice.freq > 0.0 && voice.freq.is_finite())
+        .map(|(track, voice)| NoteEvent {
+            start: voice.start,
+            track,
+            freq: voice.freq,
+            len: voice.len.max(0.0).min(voice.frames as f32 / sample_rate as f32),
+            vel: voice.vel,
+            instrument: voice.instrument,
+            params: voice.params,
+        })
+        .collect::<Vec<_>>();
+    events.sort_by_key(|it| it.start);
+    events
+}
+
+/// `note track freq len vel instrument params...;`, the first atom lets a `[route note]` pick the messages.
+fn fudi(event: &NoteEvent) -> Vec<u8> {
+    let mut message = format!("note {} {} {} {} {}", event.track, event.freq, event.len, event.vel, event.instrument);
+    for param in &event.params {
+        message.push_str(&format!(" {}", param));
+    }
+    message.push_str(";\n");
+    message.into_bytes()
+}
+
+/// `/iaue/note` with an int track, float freq, len and vel, an int instrument and a float for every param.
+fn osc(event: &NoteEvent) -> Vec<u8> {
+    let mut message = Vec::new();
+    osc_string(&mut message, OSC_ADDRESS);
+    osc_string(&mut message, &format!(",ifffi{}", "f".repeat(event.params.len())));
+    message.extend((event.track as i32).to_be_bytes());
+    for value in [event.freq, event.len, event.vel] {
+        message.extend(value.to_be_bytes());
+    }
+    message.extend((event.instrument as i32).to_be_bytes());
+    for param in &event.params {
+        message.extend(param.to_be_bytes());
+    }
+    message
+}
+
+// Strings end with at least one zero and are padded to 4 bytes.
+fn osc_string(message: &mut Vec<u8>, text: &str) {
+    message.extend(text.as_bytes());
+    message.resize((message.len() / 4 + 1) * 4, 0);
+}
+
+/// Sends every note to `address` at the time it starts in the render, until the last one or `cancel`.
+/// Returns the number of notes sent.
+pub fn send(project: &Project, address: &str, protocol: Protocol, cancel: &AtomicBool) -> Result<usize, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("Can't open a UDP socket: {}", e))?;
+    socket.connect(address).map_err(|e| format!("Can't connect to {}: {}", address, e))?;
+    let sample_rate = project.settings.sample_rate as f64;
+    let started = Instant::now();
+    let mut sent = 0;
+    for event in events(project) {
+        let at = started + Duration::from_secs_f64(event.start as f64 / sample_rate);
+        while let Some(left) = at.checked_duration_since(Instant::now()) {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(sent);
+            }
+            std::thread::sleep(left.min(Duration::from_millis(50)));
+        }
+        let message = match protocol {
+            Protocol::Fudi => fudi(&event),
+            Protocol::Osc => osc(&event),
+        };
+        // Nothing may be listening yet, a refused datagram isn't worth stopping for.
+        if socket.send(&message).is_ok() {
+            sent += 1;
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::{NoteFx, NoteRow, Ratio, Track};
+
+    fn project() -> Project {
+        let note = NoteRow {
+            vel: Ratio::new(1.0, 2.0),
+            instrument: 3,
+            fx: vec![NoteFx { code: 3, args: vec![Ratio::new(1.0, 4.0), Ratio::new(2.0, 1.0)] }],
+            ..NoteRow::default()
+        };
+        let mut track = Track { notes: vec![note], ..Track::default() };
+        track.init.len = 0.5;
+        Project { tracks: vec![track], ..Project::default() }
+    }
+
+    // Sends the project to a socket standing in for Pd and returns the datagram it got.
+    fn receive(protocol: Protocol) -> Vec<u8> {
+        let pd = UdpSocket::bind("127.0.0.1:0").unwrap();
+        pd.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let address = pd.local_addr().unwrap().to_string();
+        assert_eq!(send(&project(), &address, protocol, &AtomicBool::new(false)), Ok(1));
+        let mut buf = [0; 1024];
+        let len = pd.recv(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn sends_fudi() {
+        assert_eq!(String::from_utf8(receive(Protocol::Fudi)).unwrap(), "note 0 440 0.5 0.5 3 0.25 2;\n");
+    }
+
+    #[test]
+    fn sends_osc() {
+        let mut expected = b"/iaue/note\0\0,ifffiff\0\0\0\0".to_vec();
+        expected.extend(0i32.to_be_bytes());
+        for value in [440.0f32, 0.5, 0.5] {
+            expected.extend(value.to_be_bytes());
+        }
+        expected.extend(3i32.to_be_bytes());
+        for value in [0.25f32, 2.0] {
+            expected.extend(value.to_be_bytes());
+        }
+        let message = receive(Protocol::Osc);
+        assert_eq!(message.len() % 4, 0);
+        assert_eq!(message, expected);
+    }
+}
@@ -0,0 +1,228 @@
+use iaue_plugin::abi::{self, Frame, FrameSlice};
+use crate::effects::BuiltinEffect;
+use crate::instruments::Builtin;
+use libloading::Library;
+use std::collections::HashMap;
+
+// Signatures used before the versioned ABI, they pass Rust slices and Vec across `extern "C"`.
+#[allow(improper_ctypes_definitions)]
+type LegacyInstrumentFn = unsafe extern "C" fn(f32, f32, f32, usize, &[f32]) -> Vec<(f32, f32)>;
+#[allow(improper_ctypes_definitions)]
+type LegacyEffectFn = unsafe extern "C" fn(&[(f32, f32)], usize, &[f32], &[Vec<(f32, f32)>]) -> Vec<(f32, f32)>;
+
+#[derive(Clone)]
+pub enum Instrument {
+    Abi(abi::InstrumentFn),
+    Legacy(LegacyInstrumentFn),
+    Builtin(Builtin),
+    Silent,
+}
+
+#[derive(Clone, Copy)]
+pub enum Effect {
+    Abi {
+        create: abi::EffectCreateFn,
+        process: abi::EffectProcessFn,
+        destroy: abi::EffectDestroyFn,
+    },
+    Legacy(LegacyEffectFn),
+    Missing,
+}
+
+pub struct Plugin {
+    instruments: HashMap<u32, Instrument>,
+    effects: HashMap<u32, Effect>,
+    // Keeps the function pointers above valid.
+    _lib: Library,
+}
+
+impl Plugin {
+    /// Loads a plugin library. Libraries exporting `iaue_abi_version` are read through their descriptor,
+    /// otherwise `f{id}` and `fx{id}` are looked up with the legacy signatures.
+    pub fn load(path: &std::path::Path, instrument_ids: &[u32], effect_ids: &[u32]) -> Result<Self, String> {
+        let lib = unsafe { Library::new(path) }.map_err(|e| e.to_string())?;
+        let mut instruments = HashMap::new();
+        let mut effects = HashMap::new();
+        let version = unsafe { lib.get::<abi::VersionFn>(abi::VERSION_SYMBOL) }.map(|it| *it);
+        match version {
+            Ok(version_fn) => {
+                let version = unsafe { version_fn() };
+                if version != abi::ABI_VERSION {
+                    return Err(format!("Plugin ABI version {} is not supported, expected {}, rebuild it with the current iaue-plugin.", version, abi::ABI_VERSION));
+                }
+                let descriptor_fn = unsafe { lib.get::<abi::DescriptorFn>(abi::DESCRIPTOR_SYMBOL) }
+                    .map_err(|_| "Plugin exports a version but no descriptor.".to_string())?;
+                let descriptor = unsafe { descriptor_fn().as_ref() }.ok_or("Plugin descriptor is null.")?;
+                if descriptor.abi_version != abi::ABI_VERSION {
+                    return Err(format!("Plugin descriptor version {} is not supported.", descriptor.abi_version));
+                }
+                for entry in unsafe { raw_slice(descriptor.instruments, descriptor.instruments_len) } {
+                    instruments.insert(entry.id, Instrument::Abi(entry.render));
+                }
+                for entry in unsafe { raw_slice(descriptor.effects, descriptor.effects_len) } {
+                    effects.insert(
+                        entry.id,
+                        Effect::Abi { create: entry.create, process: entry.process, destroy: entry.destroy },
+                    );
+                }
+            }
+            Err(_) => {
+                for id in instrument_ids {
+                    if let Ok(f) = unsafe { lib.get::<LegacyInstrumentFn>(format!("f{}", id).as_bytes()) } {
+                        instruments.insert(*id, Instrument::Legacy(*f));
+                    }
+                }
+                for id in effect_ids {
+                    if let Ok(f) = unsafe { lib.get::<LegacyEffectFn>(format!("fx{}", id).as_bytes()) } {
+                        effects.insert(*id, Effect::Legacy(*f));
+                    }
+                }
+            }
+        }
+        Ok(Plugin { instruments, effects, _lib: lib })
+    }
+
+    pub fn instrument(&self, id: u32) -> Instrument {
+        self.instruments.get(&id).cloned().unwrap_or(Instrument::Silent)
+    }
+
+    pub fn effect(&self, id: u32) -> Effect {
+        self.effects.get(&id).copied().unwrap_or(Effect::Missing)
+    }
+}
+
+unsafe fn raw_slice<'a, T>(ptr: *const T, len: usize) -> &'a [T] {
+    if ptr.is_null() || len == 0 { &[] } else { std::slice::from_raw_parts(ptr, len) }
+}
+
+/// Arguments of one instrument call.
+pub struct Note<'a> {
+    pub freq: f32,
+    pub len: f32,
+    pub vel: f32,
+    pub sample_rate: usize,
+    pub params: &'a [f32],
+}
+
+impl Instrument {
+    /// ABI and built-in instruments can render any part of a note, legacy ones only produce it whole.
+    pub fn streams(&self) -> bool {
+        matches!(self, Instrument::Abi(_) | Instrument::Builtin(_))
+    }
+
+    /// Renders the note from frame `start` on into `out` and returns how many frames were written.
+    pub fn render_block(&self, note: &Note, start: usize, out: &mut [Frame]) -> usize {
+        match self {
+            Instrument::Abi(render) => {
+                let info = abi::NoteInfo {
+                    freq: note.freq,
+                    len: note.len,
+                    vel: note.vel,
+                    sample_rate: note.sample_rate as u32,
+                    start: start as u64,
+                    params: note.params.as_ptr(),
+                    params_len: note.params.len(),
+                };
+                unsafe { render(&info, out.as_mut_ptr(), out.len()) }.min(out.len())
+            }
+            Instrument::Legacy(_) => {
+                let all = self.render_all(note);
+                let part = all.get(start..).unwrap_or(&[]);
+                let written = part.len().min(out.len());
+                out[..written].copy_from_slice(&part[..written]);
+                written
+            }
+            Instrument::Builtin(builtin) => builtin.render(note, start, out),
+            Instrument::Silent => 0,
+        }
+    }
+
+    pub fn render_all(&self, note: &Note) -> Vec<Frame> {
+        match self {
+            Instrument::Abi(_) | Instrument::Builtin(_) => {
+                let mut out = vec![Frame::default(); (note.len * note.sample_rate as f32) as usize];
+                let written = self.render_block(note, 0, &mut out);
+                out.truncate(written);
+                out
+            }
+            Instrument::Legacy(render) => {
+                let samples = unsafe { render(note.freq, note.len, note.vel, note.sample_rate, note.params) };
+                samples.into_iter().map(|(left, right)| Frame { left, right }).collect()
+            }
+            Instrument::Silent => Vec::new(),
+        }
+    }
+}
+
+/// A running effect on one track, ABI and built-in effects keep their state between blocks.
+pub enum EffectInstance {
+    Builtin(BuiltinEffect),
+    Abi {
+        state: *mut std::ffi::c_void,
+        process: abi::EffectProcessFn,
+        destroy: abi::EffectDestroyFn,
+    },
+    Legacy {
+        process: LegacyEffectFn,
+        sample_rate: usize,
+        params: Vec<f32>,
+    },
+}
+
+impl Effect {
+    /// Returns None if the effect doesn't exist and the input should stay untouched.
+    pub fn instance(&self, sample_rate: usize, params: &[f32]) -> Option<EffectInstance> {
+        match *self {
+            Effect::Abi { create, process, destroy } => {
+                let state = unsafe { create(sample_rate as u32, params.as_ptr(), params.len()) };
+                Some(EffectInstance::Abi { state, process, destroy })
+            }
+            Effect::Legacy(process) => Some(EffectInstance::Legacy { process, sample_rate, params: params.to_vec() }),
+            Effect::Missing => None,
+        }
+    }
+}
+
+impl EffectInstance {
+    /// Legacy effects only ever see the current block of their own track and of the sidechains.
+    pub fn process(&mut self, input: &[Frame], output: &mut [Frame], sidechains: &[&[Frame]]) {
+        match self {
+            EffectInstance::Builtin(effect) => effect.process(input, output),
+            EffectInstance::Abi { state, process, .. } => {
+                let slices = sidechains
+                    .iter()
+                    .map(|it| FrameSlice { ptr: it.as_ptr(), len: it.len() })
+                    .collect::<Vec<_>>();
+                let block = abi::EffectBlock {
+                    input: input.as_ptr(),
+                    output: output.as_mut_ptr(),
+                    len: input.len().min(output.len()),
+                    sidechains: slices.as_ptr(),
+                    sidechains_len: slices.len(),
+                };
+                unsafe { process(*state, &block) };
+            }
+            EffectInstance::Legacy { process, sample_rate, params } => {
+                let input = to_tuples(input);
+                let sidechains = sidechains.iter().map(|it| to_tuples(it)).collect::<Vec<_>>();
+                let result = unsafe { process(&input, *sample_rate, params, &sidechains) };
+                for (i, frame) in output.iter_mut().enumerate() {
+                    let (left, right) = result.get(i).copied().unwrap_or_default();
+                    *frame = Frame { left, right };
+                }
+            }
+        }
+    }
+}
+
+impl Drop for EffectInstance {
+    fn drop(&mut self) {
+        if let EffectInstance::Abi { state, destroy, .. } = self {
+            unsafe { destroy(*state) };
+        }
+    }
+}
+
+fn to_tuples(frames: &[Frame]) -> Vec<(f32, f32)> {
+    frames.iter().map(|it| (it.left, it.right)).collect()
+}
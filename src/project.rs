@@ -0,0 +1,593 @@
+use crate::instruments;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Ratio {
+    pub num: f32,
+    pub den: f32,
+}
+
+impl Ratio {
+    pub fn new(num: f32, den: f32) -> Self {
+        Ratio { num, den }
+    }
+    pub fn value(&self) -> f32 {
+        self.num / self.den
+    }
+    /// Parses `a`, `a/b` or `a/b/c` (divided left to right).
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.split('/').map(|it| it.trim().parse::<f32>());
+        let num = parts.next()?.ok()?;
+        let mut den = 1.0;
+        for part in parts {
+            den *= part.ok()?;
+        }
+        Some(Ratio { num, den })
+    }
+}
+
+impl Default for Ratio {
+    fn default() -> Self {
+        Ratio { num: 1.0, den: 1.0 }
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.den == 1.0 { write!(f, "{}", self.num) } else { write!(f, "{}/{}", self.num, self.den) }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NoteFx {
+    pub code: u8,
+    pub args: Vec<Ratio>,
+}
+
+impl NoteFx {
+    pub fn arg(&self, i: usize) -> Option<f32> {
+        self.args.get(i).map(|it| it.value())
+    }
+    pub fn count_arg(&self, i: usize) -> usize {
+        self.arg(i).map(|it| it as usize).unwrap_or(1)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrackFx {
+    pub id: u32,
+    pub params: Vec<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InitRow {
+    pub freq: f32,
+    pub len: f32,
+    pub vel: f32,
+    pub fx: Vec<TrackFx>,
+}
+
+impl Default for InitRow {
+    fn default() -> Self {
+        InitRow { freq: 440.0, len: 1.0, vel: 1.0, fx: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteRow {
+    pub freq: Ratio,
+    pub len: Ratio,
+    pub vel: Ratio,
+    pub instrument: u32,
+    pub fx: Vec<NoteFx>,
+}
+
+// New notes play the built-in sine, so a new project is heard without compiling a library.
+impl Default for NoteRow {
+    fn default() -> Self {
+        NoteRow { freq: Ratio::default(), len: Ratio::default(), vel: Ratio::default(), instrument: instruments::SINE, fx: Vec::new() }
+    }
+}
+
+/// Mixer strip of a track, applied after its effects when the tracks are summed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Mixer {
+    /// Volume in dB.
+    pub volume: f32,
+    /// -1 is left, 1 is right.
+    pub pan: f32,
+    pub mute: bool,
+    pub solo: bool,
+}
+
+impl Mixer {
+    /// Gains of the left and right channel, panning turns the other side down so the middle stays at full volume.
+    pub fn gains(&self) -> (f32, f32) {
+        let volume = 10f32.powf(self.volume / 20.0);
+        let pan = self.pan.clamp(-1.0, 1.0);
+        (volume * (1.0 - pan).min(1.0), volume * (1.0 + pan).min(1.0))
+    }
+
+    /// Which of `mixers` are heard, a solo silences the tracks without one and mute wins over solo.
+    pub fn audible(mixers: &[Mixer]) -> Vec<bool> {
+        let solo = mixers.iter().any(|it| it.solo);
+        mixers.iter().map(|it| !it.mute && (it.solo || !solo)).collect()
+    }
+
+    fn flags(&self) -> String {
+        match (self.mute, self.solo) {
+            (false, false) => "-".to_string(),
+            (mute, solo) => format!("{}{}", if mute { "M" } else { "" }, if solo { "S" } else { "" }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Track {
+    pub name: String,
+    /// Bus tracks have no notes, their init row only holds the fx for the tracks routed or sent to them.
+    #[serde(default)]
+    pub bus: bool,
+    #[serde(default)]
+    pub mixer: Mixer,
+    pub init: InitRow,
+    pub notes: Vec<NoteRow>,
+}
+
+impl Default for Track {
+    fn default() -> Self {
+        Track {
+            name: "name".to_string(),
+            bus: false,
+            mixer: Mixer::default(),
+            init: InitRow::default(),
+            notes: vec![NoteRow::default()],
+        }
+    }
+}
+
+// Grid rows of a track: 0 is the name, 1 the mixer strip, 2 the init row, 3.. the notes.
+pub const MIXER_ROW: usize = 1;
+pub const INIT_ROW: usize = 2;
+pub const NOTE_ROW: usize = 3;
+const NOTE_FIELDS: usize = 7;
+const INIT_FIELDS: usize = 3;
+const MIXER_FIELDS: usize = 3;
+
+fn join_ratios(args: &[Ratio]) -> String {
+    args.iter().map(|it| it.to_string()).collect::<Vec<_>>().join(",")
+}
+
+fn join_params(params: &[f32]) -> String {
+    params.iter().map(|it| it.to_string()).collect::<Vec<_>>().join(",")
+}
+
+fn parse_ratios(text: &str) -> Option<Vec<Ratio>> {
+    text.split(',').map(Ratio::parse).collect()
+}
+
+fn parse_params(text: &str) -> Option<Vec<f32>> {
+    text.split(',').map(|it| it.trim().parse::<f32>().ok()).collect()
+}
+
+impl Track {
+    /// A bus without notes or fx.
+    pub fn bus(name: String) -> Self {
+        Track { name, bus: true, notes: Vec::new(), ..Track::default() }
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.notes.len() + NOTE_ROW
+    }
+
+    // Fields of the init row before the fx pairs, a bus has no frequency, length and velocity.
+    fn init_fields(&self) -> usize {
+        if self.bus { 0 } else { INIT_FIELDS }
+    }
+
+    pub fn fields(&self, y: usize) -> Vec<String> {
+        match y {
+            0 => vec![self.name.clone()],
+            MIXER_ROW => vec![self.mixer.volume.to_string(), self.mixer.pan.to_string(), self.mixer.flags()],
+            INIT_ROW => {
+                let mut fields = vec![self.init.freq.to_string(), self.init.len.to_string(), self.init.vel.to_string()];
+                fields.truncate(self.init_fields());
+                for fx in &self.init.fx {
+                    fields.push(fx.id.to_string());
+                    fields.push(join_params(&fx.params));
+                }
+                fields
+            }
+            y => match self.notes.get(y - NOTE_ROW) {
+                Some(note) => {
+                    let mut fields = Vec::with_capacity(NOTE_FIELDS + note.fx.len() * 2);
+                    for ratio in [note.freq, note.len, note.vel] {
+                        fields.push(ratio.num.to_string());
+                        fields.push(ratio.den.to_string());
+                    }
+                    fields.push(note.instrument.to_string());
+                    for fx in &note.fx {
+                        fields.push(fx.code.to_string());
+                        fields.push(join_ratios(&fx.args));
+                    }
+                    fields
+                }
+                None => Vec::new(),
+            },
+        }
+    }
+
+    pub fn field_count(&self, y: usize) -> usize {
+        match y {
+            0 => 1,
+            MIXER_ROW => MIXER_FIELDS,
+            INIT_ROW => self.init_fields() + self.init.fx.len() * 2,
+            y => self.notes.get(y - NOTE_ROW).map(|it| NOTE_FIELDS + it.fx.len() * 2).unwrap_or(0),
+        }
+    }
+
+    /// Stores `text` into field `i` of row `y`. Returns false if it doesn't parse.
+    pub fn set_field(&mut self, y: usize, i: usize, text: &str) -> bool {
+        match y {
+            0 => {
+                self.name = text.to_string();
+                true
+            }
+            MIXER_ROW => {
+                let mixer = &mut self.mixer;
+                match i {
+                    0 | 1 => match text.parse::<f32>() {
+                        Ok(val) => {
+                            if i == 0 { mixer.volume = val } else { mixer.pan = val.clamp(-1.0, 1.0) };
+                            true
+                        }
+                        Err(_) => false,
+                    },
+                    _ => {
+                        // `M` mutes and `S` solos, `-` or nothing clears both.
+                        let text = text.trim().to_uppercase();
+                        if !text.chars().all(|it| matches!(it, 'M' | 'S' | '-')) {
+                            return false;
+                        }
+                        (mixer.mute, mixer.solo) = (text.contains('M'), text.contains('S'));
+                        true
+                    }
+                }
+            }
+            INIT_ROW => {
+                let offset = self.init_fields();
+                let init = &mut self.init;
+                match i {
+                    i if i < offset => match text.parse::<f32>() {
+                        Ok(val) => {
+                            match i {
+                                0 => init.freq = val,
+                                1 => init.len = val,
+                                _ => init.vel = val,
+                            }
+                            true
+                        }
+                        Err(_) => false,
+                    },
+                    i => {
+                        let Some(fx) = init.fx.get_mut((i - offset) / 2) else { return false };
+                        if (i - offset).is_multiple_of(2) {
+                            text.parse().map(|id| fx.id = id).is_ok()
+                        } else {
+                            parse_params(text).map(|params| fx.params = params).is_some()
+                        }
+                    }
+                }
+            }
+            y => {
+                let Some(note) = self.notes.get_mut(y - NOTE_ROW) else { return false };
+                match i {
+                    0..=5 => match text.parse::<f32>() {
+                        Ok(val) => {
+                            let ratio = match i / 2 {
+                                0 => &mut note.freq,
+                                1 => &mut note.len,
+                                _ => &mut note.vel,
+                            };
+                            if i.is_multiple_of(2) { ratio.num = val } else { ratio.den = val };
+                            true
+                        }
+                        Err(_) => false,
+                    },
+                    6 => text.parse().map(|id| note.instrument = id).is_ok(),
+                    i => {
+                        let Some(fx) = note.fx.get_mut((i - NOTE_FIELDS) / 2) else { return false };
+                        if (i - NOTE_FIELDS).is_multiple_of(2) {
+                            text.parse().map(|code| fx.code = code).is_ok()
+                        } else {
+                            parse_ratios(text).map(|args| fx.args = args).is_some()
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Appends an empty fx pair to row `y`.
+    pub fn push_fx(&mut self, y: usize) {
+        match y {
+            0 | MIXER_ROW => {}
+            INIT_ROW => self.init.fx.push(TrackFx { id: 0, params: vec![0.0] }),
+            y => {
+                if let Some(note) = self.notes.get_mut(y - NOTE_ROW) {
+                    note.fx.push(NoteFx { code: 0, args: vec![Ratio::new(0.0, 1.0)] });
+                }
+            }
+        }
+    }
+
+    /// Removes the fx pair that field `i` of row `y` belongs to.
+    pub fn remove_fx(&mut self, y: usize, i: usize) {
+        match y {
+            0 | MIXER_ROW => {}
+            INIT_ROW if i >= self.init_fields() && (i - self.init_fields()) / 2 < self.init.fx.len() => {
+                self.init.fx.remove((i - self.init_fields()) / 2);
+            }
+            INIT_ROW => {}
+            y => {
+                if let Some(note) = self.notes.get_mut(y - NOTE_ROW) {
+                    if i >= NOTE_FIELDS && (i - NOTE_FIELDS) / 2 < note.fx.len() {
+                        note.fx.remove((i - NOTE_FIELDS) / 2);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub sample_rate: u32,
+    pub tempo: f32,
+    /// Bit depth of rendered files, 16 and 24 are dithered integers, 32 is float.
+    pub bit_depth: u32,
+    pub normalize: Normalize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings { sample_rate: 44100, tempo: 120.0, bit_depth: 32, normalize: Normalize::Off }
+    }
+}
+
+/// Gain applied to a whole render before it is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalize {
+    #[default]
+    Off,
+    /// Target peak in dBFS.
+    Peak(f32),
+    /// Target integrated loudness in LUFS.
+    Lufs(f32),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    pub title: String,
+    pub author: String,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Instrument {
+    pub id: u32,
+    #[serde(default)]
+    pub name: String,
+}
+
+/// A WAV file for the built-in sample player, relative paths start at the project directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sample {
+    pub path: String,
+    /// Frequency at which the file plays at its own speed.
+    pub root: f32,
+    /// Seconds trimmed from the start, and where the sample ends, 0 keeps it to the end of the file.
+    pub start: f32,
+    pub end: f32,
+}
+
+impl Default for Sample {
+    fn default() -> Self {
+        Sample { path: String::new(), root: 440.0, start: 0.0, end: 0.0 }
+    }
+}
+
+/// A connection of the routing graph, tracks are counted from 0 like in `tracks`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    /// The fx of `track` get `key` after its fx and mixer strip as their sidechain.
+    Sidechain { track: usize, key: usize },
+    /// `percent` of `from` after its fx and mixer strip is added to `to` before its fx.
+    Send { from: usize, to: usize, percent: f32 },
+    /// All of `from` after its fx and mixer strip goes to `to` before its fx instead of the master.
+    Output { from: usize, to: usize },
+}
+
+impl Route {
+    /// The track that has to be processed first and the one that depends on it.
+    pub fn edge(&self) -> (usize, usize) {
+        match *self {
+            Route::Sidechain { track, key } => (key, track),
+            Route::Send { from, to, .. } | Route::Output { from, to } => (from, to),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Project {
+    pub settings: Settings,
+    pub metadata: Metadata,
+    pub instruments: Vec<Instrument>,
+    pub samples: Vec<Sample>,
+    pub tracks: Vec<Track>,
+    pub routes: Vec<Route>,
+}
+
+impl Default for Project {
+    fn default() -> Self {
+        Project {
+            settings: Settings::default(),
+            metadata: Metadata::default(),
+            instruments: Vec::new(),
+            samples: Vec::new(),
+            tracks: vec![Track::default()],
+            routes: Vec::new(),
+        }
+    }
+}
+
+// Version 0 is the bare nested array of grid cells written before the format had a header.
+// Version 2 adds samples, bit depth and normalize settings, mixer strips, routes and bus tracks.
+// Bump it whenever the schema grows, so an older build refuses a file it would lose data of on save.
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize)]
+struct ProjectFileRef<'a> {
+    version: u32,
+    #[serde(flatten)]
+    project: &'a Project,
+}
+
+impl Project {
+    /// Removes the tracks in `range` with their routes, the routes of the tracks after them move along.
+    pub fn remove_tracks(&mut self, range: std::ops::RangeInclusive<usize>) {
+        self.tracks.drain(range.clone());
+        let (end, count) = (*range.end(), range.clone().count());
+        let shift = |track: &mut usize| {
+            if *track > end {
+                *track -= count;
+            }
+        };
+        self.routes.retain(|it| {
+            let (from, to) = it.edge();
+            !range.contains(&from) && !range.contains(&to)
+        });
+        for route in &mut self.routes {
+            match route {
+                Route::Sidechain { track, key } => {
+                    shift(track);
+                    shift(key);
+                }
+                Route::Send { from, to, .. } | Route::Output { from, to } => {
+                    shift(from);
+                    shift(to);
+                }
+            }
+        }
+    }
+
+    /// Parses a project file of any known version, returns the project and the version it was stored in.
+    pub fn from_json(data: &str) -> Result<(Self, u32), String> {
+        let value = serde_json::from_str::<serde_json::Value>(data).map_err(|e| e.to_string())?;
+        if value.is_array() {
+            let cols = serde_json::from_value::<Vec<Vec<Vec<String>>>>(value).map_err(|e| e.to_string())?;
+            return Ok((Project::from_cols(cols), 0));
+        }
+        let version = value
+            .get("version")
+            .and_then(|it| it.as_u64())
+            .ok_or("missing format version")? as u32;
+        if version > FORMAT_VERSION {
+            return Err(format!("format version {} is newer than supported {}", version, FORMAT_VERSION));
+        }
+        let project = serde_json::from_value::<Project>(value).map_err(|e| e.to_string())?;
+        Ok((project, version))
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string(&ProjectFileRef { version: FORMAT_VERSION, project: self }).map_err(|e| e.to_string())
+    }
+
+    /// Reads the nested array layout of the grid, column 0 holds line numbers.
+    fn from_cols(cols: Vec<Vec<Vec<String>>>) -> Self {
+        let parse = |it: Option<&String>| it.and_then(|it| it.parse::<f32>().ok()).unwrap_or(0.0);
+        let tracks = cols
+            .into_iter()
+            .skip(1)
+            .map(|col| {
+                let name = col.first().and_then(|it| it.first()).cloned().unwrap_or_default();
+                let init = match col.get(1) {
+                    Some(row) => InitRow {
+                        freq: parse(row.first()),
+                        len: parse(row.get(1)),
+                        vel: parse(row.get(2)),
+                        fx: row
+                            .get(INIT_FIELDS..)
+                            .unwrap_or_default()
+                            .chunks(2)
+                            .map(|fx| TrackFx {
+                                id: fx[0].parse().unwrap_or(0),
+                                params: fx
+                                    .get(1)
+                                    .map(|it| it.split(',').map(|it| it.parse::<f32>().unwrap_or(0.0)).collect())
+                                    .unwrap_or_default(),
+                            })
+                            .collect(),
+                    },
+                    None => InitRow::default(),
+                };
+                // The layout has no mixer strip, notes start right after the init row.
+                let notes = col
+                    .get(2..)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|row| NoteRow {
+                        freq: Ratio::new(parse(row.first()), parse(row.get(1))),
+                        len: Ratio::new(parse(row.get(2)), parse(row.get(3))),
+                        vel: Ratio::new(parse(row.get(4)), parse(row.get(5))),
+                        instrument: row.get(6).and_then(|it| it.parse().ok()).unwrap_or(0),
+                        fx: row
+                            .get(NOTE_FIELDS..)
+                            .unwrap_or_default()
+                            .chunks(2)
+                            .map(|fx| NoteFx {
+                                code: fx[0].parse().unwrap_or(u8::MAX),
+                                args: fx
+                                    .get(1)
+                                    // An arg that doesn't parse stays in place so the args after it keep their meaning.
+                                    .map(|it| it.split(',').map(|it| Ratio::parse(it).unwrap_or_default()).collect())
+                                    .unwrap_or_default(),
+                            })
+                            .collect(),
+                    })
+                    .collect();
+                Track { name, init, notes, ..Track::default() }
+            })
+            .collect();
+        Project { tracks, ..Project::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_newer_versions() {
+        let data = format!(r#"{{"version": {}}}"#, FORMAT_VERSION + 1);
+        assert!(Project::from_json(&data).is_err());
+        let (project, version) = Project::from_json(&Project::default().to_json().unwrap()).unwrap();
+        assert_eq!((project, version), (Project::default(), FORMAT_VERSION));
+    }
+
+    #[test]
+    fn legacy_args_keep_their_positions() {
+        let data = r#"[[["0"]], [["name"], ["440", "1", "1"], ["1", "1", "1", "1", "1", "1", "1", "6", "x,2/4"]]]"#;
+        let (project, version) = Project::from_json(data).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(project.tracks[0].notes[0].fx, [NoteFx { code: 6, args: vec![Ratio::default(), Ratio::new(2.0, 4.0)] }]);
+    }
+}
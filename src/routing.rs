@@ -0,0 +1,82 @@
+use crate::project::{Project, Route};
+
+/// Source and destination of every route between existing tracks, the source is processed first.
+fn edges(project: &Project) -> Vec<(usize, usize)> {
+    let len = project.tracks.len();
+    project.routes.iter().map(|it| it.edge()).filter(|(from, to)| *from < len && *to < len).collect()
+}
+
+/// Order in which the tracks are processed, every track comes after the tracks it is keyed from or gets sends from.
+/// Tracks that don't depend on each other keep their left to right order.
+pub fn order(project: &Project) -> Result<Vec<usize>, String> {
+    let len = project.tracks.len();
+    let edges = edges(project);
+    let mut inputs = vec![0; len];
+    for (_, to) in &edges {
+        inputs[*to] += 1;
+    }
+    let mut order = Vec::with_capacity(len);
+    let mut done = vec![false; len];
+    // The leftmost ready track goes next, without routes this is the order of the columns.
+    while let Some(track) = (0..len).find(|it| !done[*it] && inputs[*it] == 0) {
+        done[track] = true;
+        order.push(track);
+        for (_, to) in edges.iter().filter(|(from, _)| *from == track) {
+            inputs[*to] -= 1;
+        }
+    }
+    if order.len() == len {
+        return Ok(order);
+    }
+    // Every track left has an input from another track left, walking those inputs back has to come around.
+    let mut path = vec![(0..len).find(|it| !done[*it]).unwrap_or(0)];
+    loop {
+        let last = path[path.len() - 1];
+        let Some(&(from, _)) = edges.iter().find(|(from, to)| *to == last && !done[*from]) else { break };
+        if let Some(start) = path.iter().position(|it| *it == from) {
+            path.drain(..start);
+            break;
+        }
+        path.push(from);
+    }
+    path.reverse();
+    let names = path.iter().map(|it| format!("{} ({})", it + 1, project.tracks[*it].name)).collect::<Vec<_>>();
+    Err(format!("Routing cycle between columns {}.", names.join(", ")))
+}
+
+/// Describes a route with column numbers as they are shown in the editor.
+pub fn describe(project: &Project, route: &Route) -> String {
+    let name = |track: usize| match project.tracks.get(track) {
+        Some(it) => format!("{} ({})", track + 1, it.name),
+        None => format!("{} (missing)", track + 1),
+    };
+    match *route {
+        Route::Sidechain { track, key } => format!("{} keyed from {}", name(track), name(key)),
+        Route::Send { from, to, percent } => format!("{} sends {}% to {}", name(from), percent, name(to)),
+        Route::Output { from, to } => format!("{} goes to {}", name(from), name(to)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::Track;
+
+    fn project(names: &[&str], routes: Vec<Route>) -> Project {
+        let tracks = names.iter().map(|it| Track { name: it.to_string(), ..Track::default() }).collect();
+        Project { tracks, routes, ..Project::default() }
+    }
+
+    #[test]
+    fn orders_sidechain_chains() {
+        // Columns c, b, a where a keys b and b keys c, so a goes first although it is the rightmost.
+        let routes = vec![Route::Sidechain { track: 0, key: 1 }, Route::Sidechain { track: 1, key: 2 }];
+        assert_eq!(order(&project(&["c", "b", "a"], routes)), Ok(vec![2, 1, 0]));
+    }
+
+    #[test]
+    fn names_the_columns_of_a_cycle() {
+        let routes = vec![Route::Send { from: 0, to: 1, percent: 50.0 }, Route::Send { from: 1, to: 0, percent: 50.0 }];
+        assert_eq!(order(&project(&["a", "b"], routes)), Err("Routing cycle between columns 2 (b), 1 (a).".to_string()));
+    }
+}
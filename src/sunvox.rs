@@ -0,0 +1,294 @@
+use crate::engine;
+use crate::instruments::{Builtin, SampleData};
+use crate::midi;
+use crate::plugin::Note;
+use crate::project::Project;
+use iaue_plugin::Frame;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+// One line is a tick at speed 1, SunVox has 24 ticks per beat.
+const LINES_PER_BEAT: f64 = 24.0;
+// Long columns are split into patterns of this many lines placed one after another.
+const PATTERN_LINES: usize = 4096;
+const NOTE_OFF: u8 = 128;
+const FLAG_EXISTS: u32 = 1;
+const FLAG_GENERATOR: u32 = 1 << 1;
+// The Sampler maps notes 1 to 96 (C0 to B7) to its samples.
+const SAMPLER_NOTES: usize = 96;
+// A sample plays at its own rate on C5 when it has no relative note.
+const BASE_NOTE: i32 = 61;
+// Sample data chunks hold interleaved stereo 32 bit floats.
+const SAMPLE_FLOAT: u32 = 4;
+const SAMPLE_STEREO: u32 = 8;
+// Stereo flag of the sample type byte.
+const TYPE_STEREO: u8 = 0x40;
+
+fn chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend(id);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+}
+
+fn chunk_u32(out: &mut Vec<u8>, id: &[u8; 4], value: u32) {
+    chunk(out, id, &value.to_le_bytes());
+}
+
+// Zero terminated and padded like the names SunVox writes.
+fn name(text: &str, len: usize) -> Vec<u8> {
+    let mut data = text.as_bytes().iter().copied().take(len - 1).collect::<Vec<_>>();
+    data.resize(len, 0);
+    data
+}
+
+/// A distinct note of a column rendered once, the pattern plays it on `note`.
+struct SampleNote {
+    note: u8,
+    frames: Vec<Frame>,
+}
+
+/// Writes a SunVox project with a pattern and a Sampler module per column, the modules go straight to the output.
+/// Every distinct note of a column (frequency, length, instrument and params) is rendered with the built-in instruments
+/// into a sample of its Sampler, on the note nearest to its key that is still free. Velocity is left to the pattern.
+/// Returns the notes written and skipped, notes of library instruments and past the 96 notes of a Sampler are skipped.
+pub fn export(project: &Project, path: &Path) -> Result<(usize, usize), String> {
+    let sample_rate = project.settings.sample_rate as usize;
+    let lines_per_frame = project.settings.tempo as f64 / 60.0 * LINES_PER_BEAT / sample_rate as f64;
+    let samples = project
+        .samples
+        .iter()
+        .map(|it| SampleData::load(it).map(Arc::new))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (mut written, mut skipped) = (0, 0);
+    let mut out = Vec::new();
+    chunk(&mut out, b"SVOX", &[]);
+    chunk_u32(&mut out, b"VERS", 0x0201_0000);
+    chunk_u32(&mut out, b"BVER", 0x0200_0000);
+    chunk_u32(&mut out, b"SFGS", 0);
+    chunk_u32(&mut out, b"BPM ", project.settings.tempo.round().max(1.0) as u32);
+    chunk_u32(&mut out, b"SPED", 1);
+    chunk_u32(&mut out, b"TGRD", 24);
+    chunk_u32(&mut out, b"TGD2", 6);
+    chunk_u32(&mut out, b"GVOL", 80);
+    chunk(&mut out, b"NAME", &name(&project.metadata.title, project.metadata.title.len() + 1));
+
+    let mut modules = Vec::new();
+    for (i, track) in project.tracks.iter().enumerate() {
+        let mut voices = engine::schedule(project, i, sample_rate);
+        voices.sort_by_key(|it| it.start);
+        let mut sample_notes: Vec<SampleNote> = Vec::new();
+        let mut rendered = HashMap::new();
+        // Sounding notes get a pattern track each, a track is reused once its note has ended.
+        let mut tracks: Vec<usize> = Vec::new();
+        let mut events = Vec::new();
+        for voice in voices {
+            let frames = voice.frames.min((voice.len.max(0.0) * sample_rate as f32) as usize);
+            if voice.vel <= 0.0 || voice.freq <= 0.0 || !voice.freq.is_finite() || frames == 0 {
+                continue;
+            }
+            let id = (voice.freq.to_bits(), frames, voice.instrument, voice.params.iter().map(|it| it.to_bits()).collect::<Vec<_>>());
+            let note = match rendered.get(&id) {
+                Some(&note) => note,
+                None => {
+                    let Some(instrument) = Builtin::get(voice.instrument, &voice.params, &samples) else {
+                        skipped += 1;
+                        continue;
+                    };
+                    let (key, _) = midi::note_of(voice.freq);
+                    let Some(note) = free_note(&sample_notes, key + 1) else {
+                        skipped += 1;
+                        continue;
+                    };
+                    let note_info = Note { freq: voice.freq, len: voice.len, vel: 1.0, sample_rate, params: &voice.params };
+                    let mut audio = vec![Frame::default(); frames];
+                    let len = instrument.render(&note_info, 0, &mut audio);
+                    audio.truncate(len);
+                    for frame in &mut audio {
+                        *frame = Frame { left: engine::sanitize(frame.left), right: engine::sanitize(frame.right) };
+                    }
+                    sample_notes.push(SampleNote { note, frames: audio });
+                    rendered.insert(id, note);
+                    note
+                }
+            };
+            written += 1;
+            let start = (voice.start as f64 * lines_per_frame).round() as usize;
+            let end = ((voice.start + frames) as f64 * lines_per_frame).round() as usize;
+            let end = end.max(start + 1);
+            let slot = match tracks.iter().position(|&until| until <= start) {
+                Some(slot) => slot,
+                None => {
+                    tracks.push(0);
+                    tracks.len() - 1
+                }
+            };
+            tracks[slot] = end;
+            // Velocity goes up to 128.
+            let vel = ((voice.vel * 128.0).round() as u8).clamp(1, 128);
+            events.push((start, slot, note, vel));
+            events.push((end, slot, NOTE_OFF, 0));
+        }
+        let lines = events.iter().map(|it| it.0 + 1).max().unwrap_or(1);
+        let channels = tracks.len().max(1);
+        let module = modules.len() as u16 + 1;
+        for (part, first_line) in (0..lines).step_by(PATTERN_LINES).enumerate() {
+            let part_lines = PATTERN_LINES.min(lines - first_line);
+            let mut data = vec![0u8; part_lines * channels * 8];
+            for &(line, slot, note, vel) in events.iter().filter(|it| (first_line..first_line + part_lines).contains(&it.0)) {
+                let cell = ((line - first_line) * channels + slot) * 8;
+                // A note off at the start of a note on the same track would cut it, the note wins.
+                if note == NOTE_OFF && data[cell] != 0 {
+                    continue;
+                }
+                data[cell] = note;
+                data[cell + 1] = vel;
+                data[cell + 2..cell + 4].copy_from_slice(&(module + 1).to_le_bytes());
+            }
+            chunk(&mut out, b"PDTA", &data);
+            let title = if part == 0 { track.name.clone() } else { format!("{} {}", track.name, part + 1) };
+            chunk(&mut out, b"PNME", &name(&title, 32));
+            chunk_u32(&mut out, b"PCHN", channels as u32);
+            chunk_u32(&mut out, b"PLIN", part_lines as u32);
+            chunk_u32(&mut out, b"PYSZ", 32);
+            chunk_u32(&mut out, b"PFLG", 0);
+            chunk(&mut out, b"PICO", &[0; 32]);
+            chunk(&mut out, b"PFGC", &[0, 0, 0]);
+            chunk(&mut out, b"PBGC", &[255, 255, 255]);
+            chunk_u32(&mut out, b"PFFF", 0);
+            chunk_u32(&mut out, b"PXXX", first_line as u32);
+            chunk_u32(&mut out, b"PYYY", (i * 32) as u32);
+            chunk(&mut out, b"PEND", &[]);
+        }
+        modules.push((track.name.clone(), sampler_chunks(&track.name, &sample_notes, sample_rate)));
+    }
+
+    // Module 0 is the output, every column module is linked into it.
+    let links = (1..=modules.len() as i32).flat_map(|it| it.to_le_bytes()).collect::<Vec<_>>();
+    write_module(&mut out, "Output", "Output", FLAG_EXISTS, 512, &links, &[]);
+    for (i, (title, chunks)) in modules.iter().enumerate() {
+        write_module(&mut out, title, "Sampler", FLAG_EXISTS | FLAG_GENERATOR, 128 + (i as i32 % 8) * 48, &[], chunks);
+    }
+    std::fs::write(path, out).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+    Ok((written, skipped))
+}
+
+// Note nearest to `wanted` that no sample has yet, counted from 1 like the pattern notes.
+fn free_note(sample_notes: &[SampleNote], wanted: i32) -> Option<u8> {
+    let wanted = wanted.clamp(1, SAMPLER_NOTES as i32);
+    (0..SAMPLER_NOTES as i32)
+        .flat_map(|distance| [wanted - distance, wanted + distance])
+        .filter(|it| (1..=SAMPLER_NOTES as i32).contains(it))
+        .map(|it| it as u8)
+        .find(|note| sample_notes.iter().all(|it| it.note != *note))
+}
+
+// Data chunks of a Sampler: chunk 0 is the instrument with the note to sample map, then a header and a data chunk per sample.
+fn sampler_chunks(title: &str, sample_notes: &[SampleNote], sample_rate: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut instrument = name(title, 22);
+    instrument.push(0);
+    instrument.extend((sample_notes.len() as u16).to_le_bytes());
+    let mut map = [0u8; SAMPLER_NOTES];
+    for (i, sample) in sample_notes.iter().enumerate() {
+        map[sample.note as usize - 1] = i as u8;
+    }
+    instrument.extend(map);
+    // Volume and panning envelope points and their settings are all off.
+    instrument.extend([0; 24 * 2 * 2 + 14]);
+    // Fadeout, then volume, finetune, panning and relative note of the whole instrument.
+    instrument.extend(0u16.to_le_bytes());
+    instrument.extend([64, 0, 128, 0]);
+    chunk_u32(&mut out, b"CHNK", sample_notes.len() as u32 * 2 + 1);
+    chunk_u32(&mut out, b"CHNM", 0);
+    chunk(&mut out, b"CHDT", &instrument);
+    for (i, sample) in sample_notes.iter().enumerate() {
+        let mut header = Vec::with_capacity(40);
+        header.extend((sample.frames.len() as u32).to_le_bytes());
+        // No loop.
+        header.extend([0; 8]);
+        let relative = (BASE_NOTE - sample.note as i32) as i8;
+        header.extend([64, 0, TYPE_STEREO, 128, relative as u8, 0]);
+        header.extend(name(&format!("{}", i + 1), 22));
+        chunk_u32(&mut out, b"CHNM", i as u32 * 2 + 1);
+        chunk(&mut out, b"CHDT", &header);
+        let data = sample.frames.iter().flat_map(|it| [it.left, it.right]).flat_map(|it| it.to_le_bytes()).collect::<Vec<_>>();
+        chunk_u32(&mut out, b"CHNM", i as u32 * 2 + 2);
+        chunk(&mut out, b"CHDT", &data);
+        chunk_u32(&mut out, b"CHFF", SAMPLE_FLOAT | SAMPLE_STEREO);
+        chunk_u32(&mut out, b"CHFR", sample_rate as u32);
+    }
+    out
+}
+
+fn write_module(out: &mut Vec<u8>, title: &str, kind: &str, flags: u32, x: i32, links: &[u8], chunks: &[u8]) {
+    chunk_u32(out, b"SFFF", flags);
+    chunk(out, b"SNAM", &name(title, 32));
+    chunk(out, b"STYP", &name(kind, kind.len() + 1));
+    chunk(out, b"SFIN", &0i32.to_le_bytes());
+    chunk(out, b"SREL", &0i32.to_le_bytes());
+    chunk(out, b"SXXX", &x.to_le_bytes());
+    chunk(out, b"SYYY", &256i32.to_le_bytes());
+    chunk(out, b"SZZZ", &0i32.to_le_bytes());
+    chunk_u32(out, b"SSCL", 256);
+    chunk(out, b"SLNK", links);
+    out.extend(chunks);
+    chunk(out, b"SEND", &[]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::{NoteRow, Ratio, Track};
+
+    #[test]
+    fn writes_a_sampler_per_column() {
+        let fifth = NoteRow { freq: Ratio::new(3.0, 2.0), ..NoteRow::default() };
+        let back = NoteRow { freq: Ratio::new(2.0, 3.0), ..NoteRow::default() };
+        let mut melody = Track { name: "melody".to_string(), notes: vec![NoteRow::default(), fifth, back], ..Track::default() };
+        melody.init.len = 0.25;
+        let mut bass = Track { name: "bass".to_string(), ..Track::default() };
+        bass.init.freq = 110.0;
+        bass.init.len = 0.25;
+        let project = Project { tracks: vec![melody, bass], ..Project::default() };
+        let path = std::env::temp_dir().join(format!("iaue-sunvox-{}.sunvox", std::process::id()));
+        assert_eq!(export(&project, &path).unwrap(), (4, 0));
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let mut chunks = Vec::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            chunks.push((&rest[..4], &rest[8..8 + len]));
+            rest = &rest[8 + len..];
+        }
+        assert_eq!(chunks[0].0, b"SVOX");
+        let patterns = chunks.iter().filter(|it| it.0 == b"PDTA").map(|it| it.1).collect::<Vec<_>>();
+        assert_eq!(patterns.len(), 2);
+        // Every note of a pattern plays the Sampler of its column, module 0 is the output.
+        for (i, pattern) in patterns.iter().enumerate() {
+            assert!(pattern.chunks(8).filter(|it| it[0] != 0).all(|it| u16::from_le_bytes([it[2], it[3]]) == i as u16 + 2));
+        }
+        // Data chunks of each Sampler: the instrument, then a header and the frames of every sample.
+        let mut samplers = Vec::new();
+        for (id, data) in chunks {
+            match id {
+                b"STYP" if data.starts_with(b"Sampler\0") => samplers.push(Vec::new()),
+                b"CHDT" => samplers.last_mut().unwrap().push(data),
+                _ => {}
+            }
+        }
+        assert_eq!(samplers.len(), 2);
+        let sample_rate = project.settings.sample_rate as usize;
+        for (sampler, samples) in samplers.iter().zip([2, 1]) {
+            assert_eq!(sampler.len(), 1 + samples * 2);
+            assert_eq!(u16::from_le_bytes([sampler[0][23], sampler[0][24]]), samples as u16);
+            for pair in sampler[1..].chunks(2) {
+                assert_eq!(u32::from_le_bytes(pair[0][..4].try_into().unwrap()) as usize, sample_rate / 4);
+                assert_eq!(pair[1].len(), sample_rate / 4 * 8);
+                assert!(pair[1].iter().any(|it| *it != 0));
+            }
+        }
+    }
+}
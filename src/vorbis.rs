@@ -0,0 +1,748 @@
+use crate::flac::vorbis_comment;
+use iaue_plugin::Frame;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Every packet is a long block, the short size is only there because the header needs one.
+const SHORT_EXP: u32 = 8;
+const LONG_EXP: u32 = 11;
+const BLOCK: usize = 1 << LONG_EXP;
+const HALF: usize = BLOCK / 2;
+
+// Floor posts besides 0 and HALF, coarse ones first so the fine ones are predicted from them.
+const POSTS: [u32; 24] = [256, 64, 512, 16, 128, 384, 768, 4, 32, 96, 192, 320, 448, 640, 896, 8, 24, 48, 80, 160, 224, 576, 2, 12];
+const POSTS_PER_PARTITION: usize = 4;
+const MULTIPLIER: i32 = 2;
+const RANGE: i32 = 128;
+// The floor is the quantization step, the loudest coefficient near a post becomes this many steps.
+const STEPS: f32 = 24.0;
+const MIN_FLOOR: f32 = 1e-6;
+// Half of what the coarse and fine books reach, the angle of two coupled values can be twice as big.
+const MAX_RESIDUE: i32 = 63;
+const PARTITION: usize = 32;
+const COARSE: i32 = 15;
+
+// Codebooks in the setup header.
+const FLOOR_BOOK: usize = 0;
+const CLASS_BOOK: usize = 1;
+const SMALL_BOOK: usize = 2;
+const FINE_BOOK: usize = 3;
+const COARSE_BOOK: usize = 4;
+
+/// Writes a stereo Ogg Vorbis stream, fixed long blocks with a floor that follows the loudest coefficients.
+pub struct VorbisWriter {
+    ogg: OggWriter,
+    books: Vec<Codebook>,
+    mdct: Mdct,
+    // Samples that aren't in a packet yet, a block of silence comes first so the first packet starts before the song.
+    pending: [Vec<f32>; 2],
+    packets: u64,
+    samples: u64,
+}
+
+impl VorbisWriter {
+    pub fn create(path: &Path, sample_rate: u32, comments: &[(String, String)]) -> std::io::Result<Self> {
+        let books = codebooks();
+        let mut ogg = OggWriter {
+            out: BufWriter::new(File::create(path)?),
+            serial: fastrand::u32(..),
+            sequence: 0,
+            segments: Vec::new(),
+            data: Vec::new(),
+            granule: 0,
+            ends_packet: false,
+            continued: false,
+        };
+        ogg.packet(&identification(sample_rate), 0)?;
+        ogg.flush(false)?;
+        let mut comment = vec![3];
+        comment.extend(b"vorbis");
+        comment.extend(vorbis_comment(comments));
+        comment.push(1);
+        ogg.packet(&comment, 0)?;
+        ogg.packet(&setup(&books), 0)?;
+        ogg.flush(false)?;
+        Ok(VorbisWriter { ogg, books, mdct: Mdct::new(), pending: [vec![0.0; HALF], vec![0.0; HALF]], packets: 0, samples: 0 })
+    }
+
+    pub fn write(&mut self, frames: &[Frame]) -> std::io::Result<()> {
+        for frame in frames {
+            self.pending[0].push(frame.left);
+            self.pending[1].push(frame.right);
+        }
+        self.samples += frames.len() as u64;
+        while self.pending[0].len() >= BLOCK {
+            self.encode_block()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        // Packet `j` completes the samples up to `j * HALF`, the last page needs one after the page of the first.
+        while (self.packets as usize).saturating_sub(1) * HALF < self.samples as usize || self.packets < 2 {
+            for channel in &mut self.pending {
+                channel.resize(channel.len().max(BLOCK), 0.0);
+            }
+            self.encode_block()?;
+        }
+        self.ogg.granule = self.samples as i64;
+        self.ogg.flush(true)?;
+        self.ogg.out.flush()
+    }
+
+    fn encode_block(&mut self) -> std::io::Result<()> {
+        let spectrum = [self.mdct.forward(&self.pending[0][..BLOCK]), self.mdct.forward(&self.pending[1][..BLOCK])];
+        for channel in &mut self.pending {
+            channel.drain(..HALF);
+        }
+        let packet = audio_packet(&self.books, &spectrum);
+        let granule = self.packets as i64 * HALF as i64;
+        self.packets += 1;
+        // Flushed before the packet, so the last page always has one to end the stream with.
+        if self.ogg.data.len() >= 4096 {
+            self.ogg.flush(false)?;
+        }
+        self.ogg.packet(&packet, granule)?;
+        // The first audio packet gets a page of its own. Decoders only cut the last packet down to the final granule
+        // when an earlier page ended on one, otherwise a song shorter than a block would come out a block long.
+        if self.packets == 1 {
+            self.ogg.flush(false)?;
+        }
+        Ok(())
+    }
+}
+
+fn identification(sample_rate: u32) -> Vec<u8> {
+    let mut packet = vec![1];
+    packet.extend(b"vorbis");
+    packet.extend(0u32.to_le_bytes());
+    packet.push(2);
+    packet.extend(sample_rate.to_le_bytes());
+    // Maximum, nominal and minimum bitrate are unknown.
+    packet.extend([0; 12]);
+    packet.push((SHORT_EXP | LONG_EXP << 4) as u8);
+    packet.push(1);
+    packet
+}
+
+fn setup(books: &[Codebook]) -> Vec<u8> {
+    let mut out = BitPacker::default();
+    out.put(5, 8);
+    for byte in b"vorbis" {
+        out.put(*byte as u32, 8);
+    }
+    out.put(books.len() as u32 - 1, 8);
+    for book in books {
+        book.write_header(&mut out);
+    }
+    // One unused time domain transform.
+    out.put(0, 6);
+    out.put(0, 16);
+
+    // Floor 1, every partition of posts has the same class with one book.
+    out.put(0, 6);
+    out.put(1, 16);
+    let partitions = POSTS.len() / POSTS_PER_PARTITION;
+    out.put(partitions as u32, 5);
+    for _ in 0..partitions {
+        out.put(0, 4);
+    }
+    out.put(POSTS_PER_PARTITION as u32 - 1, 3);
+    out.put(0, 2);
+    out.put(FLOOR_BOOK as u32 + 1, 8);
+    out.put(MULTIPLIER as u32 - 1, 2);
+    out.put(HALF.trailing_zeros(), 4);
+    for post in POSTS {
+        out.put(post, HALF.trailing_zeros());
+    }
+
+    // Residue 1 over the whole half block, classes are silence, up to 1, up to 7 and coarse plus fine.
+    out.put(0, 6);
+    out.put(1, 16);
+    out.put(0, 24);
+    out.put(HALF as u32, 24);
+    out.put(PARTITION as u32 - 1, 24);
+    out.put(3, 6);
+    out.put(CLASS_BOOK as u32, 8);
+    for cascade in [0b000, 0b001, 0b001, 0b011] {
+        out.put(cascade, 3);
+        out.put(0, 1);
+    }
+    for book in [SMALL_BOOK, FINE_BOOK, COARSE_BOOK, FINE_BOOK] {
+        out.put(book as u32, 8);
+    }
+
+    // Mapping 0 couples left as magnitude and right as angle.
+    out.put(0, 6);
+    out.put(0, 16);
+    out.put(0, 1);
+    out.put(1, 1);
+    out.put(0, 8);
+    out.put(0, 1);
+    out.put(1, 1);
+    out.put(0, 2);
+    out.put(0, 8);
+    out.put(0, 8);
+    out.put(0, 8);
+
+    // A single long block mode.
+    out.put(0, 6);
+    out.put(1, 1);
+    out.put(0, 16);
+    out.put(0, 16);
+    out.put(0, 8);
+    out.put(1, 1);
+    out.bytes
+}
+
+fn audio_packet(books: &[Codebook], spectrum: &[Vec<f32>; 2]) -> Vec<u8> {
+    let mut out = BitPacker::default();
+    // Audio packet, mode 0 takes no bits, long blocks on both sides.
+    out.put(0, 1);
+    out.put(1, 1);
+    out.put(1, 1);
+    let loudest = spectrum.iter().flatten().fold(0f32, |max, it| max.max(it.abs()));
+    if loudest < MIN_FLOOR {
+        // Both floors unused, there is no residue then.
+        out.put(0, 1);
+        out.put(0, 1);
+        return out.bytes;
+    }
+
+    let floor = Floor::fit(spectrum);
+    for _ in spectrum {
+        out.put(1, 1);
+        out.put(floor.values[0], 7);
+        out.put(floor.values[1], 7);
+        for value in &floor.values[2..] {
+            books[FLOOR_BOOK].write(&mut out, *value as usize);
+        }
+    }
+
+    // Both channels share the floor, so the coupling only sees whole steps.
+    let mut residue = [vec![0i32; HALF], vec![0i32; HALF]];
+    for k in 0..HALF {
+        let quantize = |value: f32| ((value / floor.curve[k]).round() as i32).clamp(-MAX_RESIDUE, MAX_RESIDUE);
+        let (magnitude, angle) = couple(quantize(spectrum[0][k]), quantize(spectrum[1][k]));
+        residue[0][k] = magnitude;
+        residue[1][k] = angle;
+    }
+    let classes = residue.each_ref().map(|channel| {
+        channel
+            .chunks(PARTITION)
+            .map(|part| match part.iter().map(|it| it.abs()).max().unwrap_or(0) {
+                0 => 0,
+                1 => 1,
+                2..=7 => 2,
+                _ => 3,
+            })
+            .collect::<Vec<usize>>()
+    });
+    let partitions = HALF / PARTITION;
+    for pass in 0..2 {
+        for first in (0..partitions).step_by(2) {
+            if pass == 0 {
+                for channel in &classes {
+                    books[CLASS_BOOK].write(&mut out, channel[first] * 4 + channel[first + 1]);
+                }
+            }
+            for partition in first..first + 2 {
+                for (channel, classes) in residue.iter().zip(&classes) {
+                    let part = &channel[partition * PARTITION..(partition + 1) * PARTITION];
+                    match (classes[partition], pass) {
+                        (1, 0) => part.chunks(4).for_each(|it| books[SMALL_BOOK].write_vector(&mut out, it)),
+                        (2, 0) => part.chunks(2).for_each(|it| books[FINE_BOOK].write_vector(&mut out, it)),
+                        (3, 0) => part
+                            .chunks(2)
+                            .for_each(|it| books[COARSE_BOOK].write_vector(&mut out, &[coarse(it[0]), coarse(it[1])])),
+                        (3, 1) => part.chunks(2).for_each(|it| {
+                            books[FINE_BOOK].write_vector(&mut out, &[it[0] - coarse(it[0]), it[1] - coarse(it[1])])
+                        }),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    out.bytes
+}
+
+fn coarse(value: i32) -> i32 {
+    (value as f32 / COARSE as f32).round() as i32 * COARSE
+}
+
+// Square polar mapping, the decoder turns magnitude and angle back into exactly these left and right values.
+fn couple(left: i32, right: i32) -> (i32, i32) {
+    if left > right {
+        if left > 0 { (left, left - right) } else { (right, right - left) }
+    } else if right > 0 {
+        (right, left - right)
+    } else if left < right {
+        (left, right - left)
+    } else {
+        (right, 0)
+    }
+}
+
+/// The posts of a floor 1 as coded in the packet and the curve the decoder builds from them.
+struct Floor {
+    values: Vec<u32>,
+    curve: Vec<f32>,
+}
+
+impl Floor {
+    fn fit(spectrum: &[Vec<f32>; 2]) -> Floor {
+        let xs = [0, HALF as u32].into_iter().chain(POSTS).map(|it| it as usize).collect::<Vec<_>>();
+        let mut sorted = xs.clone();
+        sorted.sort_unstable();
+        // Every post covers both segments next to it, a line between two posts then stays above the loudest coefficient.
+        let targets = xs
+            .iter()
+            .map(|x| {
+                let i = sorted.binary_search(x).unwrap_or(0);
+                let (from, to) = (sorted[i.saturating_sub(1)], sorted[(i + 1).min(sorted.len() - 1)]);
+                let loudest = spectrum.iter().flat_map(|it| &it[from..to.max(from + 1)]).fold(0f32, |max, it| max.max(it.abs()));
+                to_db_index((loudest / STEPS).max(MIN_FLOOR))
+            })
+            .collect::<Vec<_>>();
+
+        let mut values = vec![targets[0] as u32, targets[1] as u32];
+        let mut final_y = vec![targets[0], targets[1]];
+        let mut used = vec![true, true];
+        for i in 2..xs.len() {
+            let low = (0..i).filter(|it| xs[*it] < xs[i]).max_by_key(|it| xs[*it]).unwrap_or(0);
+            let high = (0..i).filter(|it| xs[*it] > xs[i]).min_by_key(|it| xs[*it]).unwrap_or(1);
+            let predicted = render_point(xs[low], final_y[low], xs[high], final_y[high], xs[i]);
+            let value = encode_post(targets[i], predicted);
+            values.push(value as u32);
+            final_y.push(if value == 0 { predicted } else { targets[i] });
+            used.push(value != 0);
+            if value != 0 {
+                used[low] = true;
+                used[high] = true;
+            }
+        }
+
+        let mut order = (0..xs.len()).collect::<Vec<_>>();
+        order.sort_by_key(|it| xs[*it]);
+        let mut curve = vec![0; HALF];
+        let (mut lx, mut ly) = (0, final_y[0] * MULTIPLIER);
+        for i in order.into_iter().skip(1).filter(|it| used[*it]) {
+            let (hx, hy) = (xs[i], final_y[i] * MULTIPLIER);
+            render_line(lx, ly, hx, hy, &mut curve);
+            (lx, ly) = (hx, hy);
+        }
+        let curve = curve.into_iter().map(from_db_index).collect();
+        Floor { values, curve }
+    }
+}
+
+// The inverse dB table of the decoder goes from about -140 dB to 0 dB in 256 even steps.
+fn from_db_index(index: i32) -> f32 {
+    1e-7 * 10f32.powf(7.0 * (index + 1) as f32 / 256.0)
+}
+
+fn to_db_index(amplitude: f32) -> i32 {
+    let index = (amplitude / 1e-7).log10() * 256.0 / 7.0 - 1.0;
+    // Rounded up so the floor isn't below the target.
+    ((index / MULTIPLIER as f32).ceil() as i32).clamp(0, RANGE - 1)
+}
+
+fn render_point(x0: usize, y0: i32, x1: usize, y1: i32, x: usize) -> i32 {
+    let dy = y1 - y0;
+    let offset = dy.abs() * (x - x0) as i32 / (x1 - x0) as i32;
+    if dy < 0 { y0 - offset } else { y0 + offset }
+}
+
+fn render_line(x0: usize, y0: i32, x1: usize, y1: i32, curve: &mut [i32]) {
+    let dy = y1 - y0;
+    let adx = (x1 - x0) as i32;
+    let base = dy / adx;
+    let step = if dy < 0 { base - 1 } else { base + 1 };
+    let ady = dy.abs() - base.abs() * adx;
+    let (mut y, mut err) = (y0, 0);
+    curve[x0] = y;
+    for value in &mut curve[x0 + 1..x1] {
+        err += ady;
+        if err >= adx {
+            err -= adx;
+            y += step;
+        } else {
+            y += base;
+        }
+        *value = y;
+    }
+}
+
+// The value the decoder unfolds into `target` around `predicted`.
+fn encode_post(target: i32, predicted: i32) -> i32 {
+    let (high_room, low_room) = (RANGE - predicted, predicted);
+    let room = high_room.min(low_room) * 2;
+    let delta = target - predicted;
+    if delta > 0 && delta * 2 < room {
+        delta * 2
+    } else if delta < 0 && -delta * 2 - 1 < room {
+        -delta * 2 - 1
+    } else if delta == 0 {
+        0
+    } else if high_room > low_room {
+        target
+    } else {
+        RANGE - 1 - target
+    }
+}
+
+struct Codebook {
+    dimensions: usize,
+    lengths: Vec<u8>,
+    codes: Vec<u32>,
+    // Minimum, step and count of the values every dimension takes.
+    lookup: Option<(i32, i32, usize)>,
+}
+
+impl Codebook {
+    /// Entry lengths come from a Huffman code over `weight`, called with the values of an entry.
+    fn new(dimensions: usize, lookup: Option<(i32, i32, usize)>, entries: usize, weight: impl Fn(usize) -> f64) -> Self {
+        let lengths = huffman_lengths(&(0..entries).map(weight).collect::<Vec<_>>());
+        let codes = codewords(&lengths);
+        Codebook { dimensions, lengths, codes, lookup }
+    }
+
+    fn write_header(&self, out: &mut BitPacker) {
+        out.put(0x564342, 24);
+        out.put(self.dimensions as u32, 16);
+        out.put(self.lengths.len() as u32, 24);
+        // Not ordered, not sparse.
+        out.put(0, 1);
+        out.put(0, 1);
+        for length in &self.lengths {
+            out.put(*length as u32 - 1, 5);
+        }
+        match self.lookup {
+            None => out.put(0, 4),
+            Some((minimum, step, count)) => {
+                out.put(1, 4);
+                out.put(pack_float(minimum as f64), 32);
+                out.put(pack_float(step as f64), 32);
+                let bits = usize::BITS - (count - 1).leading_zeros();
+                out.put(bits - 1, 4);
+                out.put(0, 1);
+                for value in 0..count {
+                    out.put(value as u32, bits);
+                }
+            }
+        }
+    }
+
+    fn write(&self, out: &mut BitPacker, entry: usize) {
+        let length = self.lengths[entry] as u32;
+        // Codewords are read from their first bit on.
+        out.put(self.codes[entry].reverse_bits() >> (32 - length), length);
+    }
+
+    // The first dimension is the lowest digit of the entry.
+    fn write_vector(&self, out: &mut BitPacker, values: &[i32]) {
+        let (minimum, step, count) = self.lookup.unwrap_or((0, 1, 1));
+        let entry = values.iter().rev().fold(0, |entry, value| entry * count + ((value - minimum) / step) as usize);
+        self.write(out, entry);
+    }
+}
+
+fn codebooks() -> Vec<Codebook> {
+    let laplace = |value: i32, scale: f64| (-(value.abs() as f64) / scale).exp();
+    let vector = |entry: usize, dimensions: usize, minimum: i32, count: usize, scale: f64| {
+        (0..dimensions).map(|i| laplace(minimum + (entry / count.pow(i as u32) % count) as i32, scale)).product::<f64>()
+    };
+    vec![
+        Codebook::new(1, None, RANGE as usize, |entry| laplace(entry as i32, 8.0) + 1e-4),
+        Codebook::new(2, None, 16, |entry| [4.0, 3.0, 2.0, 1.0][entry / 4] * [4.0, 3.0, 2.0, 1.0][entry % 4]),
+        Codebook::new(4, Some((-1, 1, 3)), 81, |entry| vector(entry, 4, -1, 3, 0.7)),
+        Codebook::new(2, Some((-7, 1, 15)), 225, |entry| vector(entry, 2, -7, 15, 2.5) + 1e-6),
+        Codebook::new(2, Some((-8 * COARSE, COARSE, 17)), 289, |entry| vector(entry, 2, -8, 17, 2.0) + 1e-6),
+    ]
+}
+
+// Lengths of a Huffman code, at least 1 and at most 32.
+fn huffman_lengths(weights: &[f64]) -> Vec<u8> {
+    let mut lengths = vec![0u8; weights.len()];
+    let mut nodes = weights.iter().enumerate().map(|(i, weight)| (*weight, vec![i])).collect::<Vec<_>>();
+    while nodes.len() > 1 {
+        nodes.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let (weight_a, a) = nodes.pop().unwrap_or_default();
+        let (weight_b, b) = nodes.pop().unwrap_or_default();
+        for leaf in a.iter().chain(&b) {
+            lengths[*leaf] += 1;
+        }
+        nodes.push((weight_a + weight_b, [a, b].concat()));
+    }
+    lengths.iter().map(|it| (*it).clamp(1, 32)).collect()
+}
+
+// Assigns the codewords like the decoder does, every entry takes the lowest free codeword of its length.
+fn codewords(lengths: &[u8]) -> Vec<u32> {
+    let mut marker = [0u32; 33];
+    let mut codes = Vec::with_capacity(lengths.len());
+    for &length in lengths {
+        let length = length as usize;
+        let mut entry = marker[length];
+        codes.push(entry);
+        for j in (1..=length).rev() {
+            if marker[j] & 1 == 1 {
+                marker[j] = if j == 1 { marker[1] + 1 } else { marker[j - 1] << 1 };
+                break;
+            }
+            marker[j] += 1;
+        }
+        for j in length + 1..33 {
+            if marker[j] >> 1 == entry {
+                entry = marker[j];
+                marker[j] = marker[j - 1] << 1;
+            } else {
+                break;
+            }
+        }
+    }
+    codes
+}
+
+// 21 bit mantissa, 10 bit exponent biased by 788 and a sign bit.
+fn pack_float(value: f64) -> u32 {
+    if value == 0.0 {
+        return 0;
+    }
+    let sign = if value < 0.0 { 0x8000_0000 } else { 0 };
+    let exponent = value.abs().log2().floor() as i32;
+    let mantissa = (value.abs() * 2f64.powi(20 - exponent)).round() as u32;
+    sign | ((exponent + 768) as u32) << 21 | mantissa
+}
+
+/// MDCT of a windowed long block through a complex FFT of a quarter of its size.
+struct Mdct {
+    window: Vec<f32>,
+    twiddle: Vec<(f64, f64)>,
+    pre: Vec<(f64, f64)>,
+    post: Vec<(f64, f64)>,
+}
+
+impl Mdct {
+    fn new() -> Self {
+        let quarter = HALF / 2;
+        let window = (0..BLOCK)
+            .map(|n| (PI / 2.0 * ((n as f64 + 0.5) / BLOCK as f64 * PI).sin().powi(2)).sin() as f32)
+            .collect();
+        let rotation = |angle: f64| (angle.cos(), -angle.sin());
+        Mdct {
+            window,
+            twiddle: (0..quarter / 2).map(|j| rotation(2.0 * PI * j as f64 / quarter as f64)).collect(),
+            pre: (0..quarter).map(|n| rotation(PI * (n as f64 + 0.25) / HALF as f64)).collect(),
+            post: (0..quarter).map(|k| rotation(PI * k as f64 / HALF as f64)).collect(),
+        }
+    }
+
+    fn forward(&self, input: &[f32]) -> Vec<f32> {
+        let x = input.iter().zip(&self.window).map(|(x, w)| (x * w) as f64).collect::<Vec<_>>();
+        // The MDCT of (a, b, c, d) is the DCT-IV of (-c reversed - d, a - b reversed).
+        let quarter = HALF / 2;
+        let folded = (0..HALF)
+            .map(|n| {
+                if n < quarter {
+                    -x[HALF + quarter - 1 - n] - x[HALF + quarter + n]
+                } else {
+                    x[n - quarter] - x[HALF + quarter - 1 - n]
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut z = (0..quarter).map(|n| multiply((folded[2 * n], folded[HALF - 1 - 2 * n]), self.pre[n])).collect::<Vec<_>>();
+        self.fft(&mut z);
+        let mut out = vec![0.0; HALF];
+        // Scaled so the unnormalized inverse of the decoder gives back the input.
+        let scale = 2.0 / HALF as f64;
+        for (k, value) in z.into_iter().enumerate() {
+            let (re, im) = multiply(value, self.post[k]);
+            out[2 * k] = (re * scale) as f32;
+            out[HALF - 1 - 2 * k] = (-im * scale) as f32;
+        }
+        out
+    }
+
+    fn fft(&self, data: &mut [(f64, f64)]) {
+        let n = data.len();
+        let bits = n.trailing_zeros();
+        for i in 0..n {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= n {
+            let stride = n / len;
+            for start in (0..n).step_by(len) {
+                for j in 0..len / 2 {
+                    let t = multiply(data[start + j + len / 2], self.twiddle[j * stride]);
+                    let u = data[start + j];
+                    data[start + j] = (u.0 + t.0, u.1 + t.1);
+                    data[start + j + len / 2] = (u.0 - t.0, u.1 - t.1);
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+fn multiply(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 * b.0 - a.1 * b.1, a.0 * b.1 + a.1 * b.0)
+}
+
+/// Packs bits least significant first, like every field of a Vorbis packet.
+#[derive(Default)]
+struct BitPacker {
+    bytes: Vec<u8>,
+    bits: u32,
+}
+
+impl BitPacker {
+    fn put(&mut self, value: u32, bits: u32) {
+        for i in 0..bits {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= ((value >> i & 1) as u8) << (self.bits % 8);
+            self.bits += 1;
+        }
+    }
+}
+
+/// Splits packets into Ogg pages of one logical stream.
+struct OggWriter {
+    out: BufWriter<File>,
+    serial: u32,
+    sequence: u32,
+    segments: Vec<u8>,
+    data: Vec<u8>,
+    // Granule of the last packet that ends on the page, -1 when none does.
+    granule: i64,
+    ends_packet: bool,
+    continued: bool,
+}
+
+impl OggWriter {
+    fn packet(&mut self, packet: &[u8], granule: i64) -> std::io::Result<()> {
+        let mut rest = packet;
+        let mut first = true;
+        loop {
+            if self.segments.len() == 255 {
+                self.flush(false)?;
+                self.continued = !first;
+            }
+            first = false;
+            let len = rest.len().min(255);
+            self.segments.push(len as u8);
+            self.data.extend(&rest[..len]);
+            rest = &rest[len..];
+            // A segment shorter than 255 ends the packet, even an empty one.
+            if len < 255 {
+                break;
+            }
+        }
+        self.granule = granule;
+        self.ends_packet = true;
+        Ok(())
+    }
+
+    fn flush(&mut self, last: bool) -> std::io::Result<()> {
+        let mut page = b"OggS\0".to_vec();
+        let header_type = self.continued as u8 | if self.sequence == 0 { 2 } else { 0 } | if last { 4 } else { 0 };
+        page.push(header_type);
+        page.extend((if self.ends_packet { self.granule } else { -1 }).to_le_bytes());
+        page.extend(self.serial.to_le_bytes());
+        page.extend(self.sequence.to_le_bytes());
+        page.extend([0; 4]);
+        page.push(self.segments.len() as u8);
+        page.extend(&self.segments);
+        page.extend(&self.data);
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        self.out.write_all(&page)?;
+        self.sequence += 1;
+        self.segments.clear();
+        self.data.clear();
+        self.ends_packet = false;
+        self.continued = false;
+        Ok(())
+    }
+}
+
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |mut crc, byte| {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { crc << 1 ^ 0x04C1_1DB7 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(len: usize) -> Vec<Frame> {
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / 44100.0;
+                Frame { left: 0.8 * (t * 440.0 * std::f32::consts::TAU).sin(), right: 0.5 * (t * 1234.5 * std::f32::consts::TAU).cos() }
+            })
+            .collect()
+    }
+
+    // Encodes `len` frames, decodes them with lewton and returns the input and the decoded frames.
+    fn round_trip(len: usize) -> (Vec<Frame>, Vec<Frame>) {
+        let path = std::env::temp_dir().join(format!("iaue-vorbis-{}-{}.ogg", std::process::id(), len));
+        let frames = signal(len);
+        let mut writer = VorbisWriter::create(&path, 44100, &[("TITLE".to_string(), "test".to_string())]).unwrap();
+        for part in frames.chunks(1000) {
+            writer.write(part).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = lewton::inside_ogg::OggStreamReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!((reader.ident_hdr.audio_sample_rate, reader.ident_hdr.audio_channels), (44100, 2));
+        assert_eq!(reader.comment_hdr.comment_list, [("TITLE".to_string(), "test".to_string())]);
+        let mut decoded = Vec::new();
+        while let Some(packet) = reader.read_dec_packet_generic::<Vec<Vec<f32>>>().unwrap() {
+            decoded.extend(packet[0].iter().zip(&packet[1]).map(|(left, right)| Frame { left: *left, right: *right }));
+        }
+        let _ = std::fs::remove_file(&path);
+        (frames, decoded)
+    }
+
+    // Signal to noise ratio in dB.
+    fn snr(frames: &[Frame], decoded: &[Frame]) -> f32 {
+        let (mut signal, mut noise) = (0.0, 0.0);
+        for (a, b) in frames.iter().zip(decoded) {
+            signal += a.left * a.left + a.right * a.right;
+            noise += (a.left - b.left).powi(2) + (a.right - b.right).powi(2);
+        }
+        10.0 * (signal / noise).log10()
+    }
+
+    #[test]
+    fn keeps_the_length() {
+        for len in [0, 1, 100, HALF - 1, HALF, HALF + 1, BLOCK, 4096, 4097] {
+            assert_eq!(round_trip(len).1.len(), len, "{} frames", len);
+        }
+    }
+
+    #[test]
+    fn decodes_close_to_the_input() {
+        for len in [4096, 4097, 44100] {
+            let (frames, decoded) = round_trip(len);
+            let snr = snr(&frames, &decoded);
+            assert!(snr > 25.0, "{} frames decode at {} dB", len, snr);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use iaue_plugin::Frame;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Bit depths `WavWriter` can write, 32 is float.
+pub const BIT_DEPTHS: [u32; 3] = [16, 24, 32];
+
+/// Writes a stereo WAV file block by block, the sizes are filled in by `finish`.
+/// 16 and 24 bit files get TPDF dither and are clamped, 32 bit files are float and keep samples over full scale.
+pub struct WavWriter {
+    out: BufWriter<File>,
+    bits: u16,
+    data_len: u32,
+}
+
+impl WavWriter {
+    /// Any bit depth other than 16 and 24 writes 32 bit float.
+    pub fn create(path: &Path, sample_rate: u32, bits: u32) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let channels: u16 = 2;
+        let bits: u16 = if bits == 16 || bits == 24 { bits as u16 } else { 32 };
+        let block_align = channels * bits / 8;
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // 1 is PCM, 3 is IEEE float.
+        out.write_all(&(if bits == 32 { 3u16 } else { 1u16 }).to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&bits.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { out, bits, data_len: 0 })
+    }
+
+    pub fn write(&mut self, frames: &[Frame]) -> std::io::Result<()> {
+        for frame in frames {
+            for sample in [frame.left, frame.right] {
+                match self.bits {
+                    16 => self.out.write_all(&(dither(sample, 32767.0) as i16).to_le_bytes())?,
+                    24 => self.out.write_all(&dither(sample, 8_388_607.0).to_le_bytes()[..3])?,
+                    _ => self.out.write_all(&sample.to_le_bytes())?,
+                }
+            }
+        }
+        let block_align = self.bits as u32 / 4;
+        self.data_len = self.data_len.saturating_add(frames.len() as u32 * block_align);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&36u32.saturating_add(self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.flush()
+    }
+}
+
+/// Scales to the integer range with triangular noise of two steps peak to peak (one step either way), then rounds and clamps.
+pub fn dither(sample: f32, scale: f32) -> i32 {
+    let noise = fastrand::f32() - fastrand::f32();
+    (sample * scale + noise).round().clamp(-scale - 1.0, scale) as i32
+}
+
+/// Reads a PCM or float WAV file, returns its sample rate and frames. Mono files are copied to both channels.
+pub fn read(path: &Path) -> Result<(u32, Vec<Frame>), String> {
+    let data = std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let invalid = || format!("{} is not a supported WAV file.", path.display());
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(invalid());
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = u32_at(pos + 4) as usize;
+        let body = pos + 8;
+        let end = body.saturating_add(size).min(data.len());
+        match id {
+            b"fmt " if size >= 16 && end - body >= 16 => {
+                let mut tag = u16_at(body);
+                // WAVE_FORMAT_EXTENSIBLE keeps the real format in the sub format GUID.
+                if tag == 0xFFFE && end - body >= 26 {
+                    tag = u16_at(body + 24);
+                }
+                format = Some((tag, u16_at(body + 2) as usize, u32_at(body + 4), u16_at(body + 14) as usize));
+            }
+            b"data" => {
+                let (tag, channels, sample_rate, bits) = format.ok_or_else(invalid)?;
+                let bytes = bits / 8;
+                if channels == 0 || bytes == 0 {
+                    return Err(invalid());
+                }
+                let sample = |i: usize| -> Option<f32> {
+                    let b = &data[i..i + bytes];
+                    Some(match (tag, bits) {
+                        (1, 8) => (b[0] as f32 - 128.0) / 128.0,
+                        (1, 16) => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+                        (1, 24) => (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0,
+                        (1, 32) => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0,
+                        (3, 32) => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                        (3, 64) => f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32,
+                        _ => return None,
+                    })
+                };
+                let frame_bytes = bytes * channels;
+                let mut frames = Vec::with_capacity((end - body) / frame_bytes);
+                for start in (body..end).step_by(frame_bytes) {
+                    if start + frame_bytes > end {
+                        break;
+                    }
+                    let left = sample(start).ok_or_else(invalid)?;
+                    let right = if channels > 1 { sample(start + bytes).ok_or_else(invalid)? } else { left };
+                    frames.push(Frame { left, right });
+                }
+                return Ok((sample_rate, frames));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size.
+        pos = body.saturating_add(size + size % 2);
+    }
+    Err(invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_a_truncated_extensible_format() {
+        // An extensible fmt chunk that claims 40 bytes but the file ends after 18 of them.
+        let mut data = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        data.extend(40u32.to_le_bytes());
+        data.extend(0xFFFEu16.to_le_bytes());
+        data.extend(2u16.to_le_bytes());
+        data.extend(44100u32.to_le_bytes());
+        data.extend([0; 6]);
+        data.extend(16u16.to_le_bytes());
+        data.extend([0; 2]);
+        let path = std::env::temp_dir().join(format!("iaue-wav-{}.wav", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let result = read(&path);
+        let _ = std::fs::remove_file(&path);
+        assert!(result.is_err());
+    }
+}
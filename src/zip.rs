@@ -0,0 +1,103 @@
+/// Builds a zip archive in memory, entries are stored without compression.
+#[derive(Default)]
+pub struct ZipWriter {
+    data: Vec<u8>,
+    // Name, CRC-32, size and offset of the local header of every entry.
+    entries: Vec<(String, u32, u32, u32)>,
+}
+
+impl ZipWriter {
+    pub fn add(&mut self, name: &str, content: &[u8]) {
+        let crc = crc32(content);
+        let offset = self.data.len() as u32;
+        self.data.extend(0x04034b50u32.to_le_bytes());
+        self.header(name, crc, content.len() as u32);
+        self.data.extend(name.as_bytes());
+        self.data.extend(content);
+        self.entries.push((name.to_string(), crc, content.len() as u32, offset));
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let start = self.data.len() as u32;
+        let entries = std::mem::take(&mut self.entries);
+        for (name, crc, size, offset) in &entries {
+            self.data.extend(0x02014b50u32.to_le_bytes());
+            // Made by version 2.0.
+            self.data.extend(20u16.to_le_bytes());
+            self.header(name, *crc, *size);
+            // Comment length, disk number, internal and external attributes.
+            self.data.extend([0; 10]);
+            self.data.extend(offset.to_le_bytes());
+            self.data.extend(name.as_bytes());
+        }
+        let directory_len = self.data.len() as u32 - start;
+        self.data.extend(0x06054b50u32.to_le_bytes());
+        // Both disk numbers.
+        self.data.extend([0; 4]);
+        self.data.extend((entries.len() as u16).to_le_bytes());
+        self.data.extend((entries.len() as u16).to_le_bytes());
+        self.data.extend(directory_len.to_le_bytes());
+        self.data.extend(start.to_le_bytes());
+        // Comment length.
+        self.data.extend([0; 2]);
+        self.data
+    }
+
+    // Fields shared by local and central headers, from the version needed to the extra field length.
+    fn header(&mut self, name: &str, crc: u32, size: u32) {
+        self.data.extend(20u16.to_le_bytes());
+        // Flags, method 0 is stored, DOS time and date of 1980-01-01.
+        self.data.extend(0u16.to_le_bytes());
+        self.data.extend(0u16.to_le_bytes());
+        self.data.extend(0u16.to_le_bytes());
+        self.data.extend(0x21u16.to_le_bytes());
+        self.data.extend(crc.to_le_bytes());
+        self.data.extend(size.to_le_bytes());
+        self.data.extend(size.to_le_bytes());
+        self.data.extend((name.len() as u16).to_le_bytes());
+        self.data.extend(0u16.to_le_bytes());
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_stored_entries() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        let mut zip = ZipWriter::default();
+        zip.add("a.txt", b"123456789");
+        let data = zip.finish();
+        let u16_at = |pos: usize| u16::from_le_bytes([data[pos], data[pos + 1]]);
+        let u32_at = |pos: usize| u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+
+        assert_eq!(u32_at(0), 0x04034b50);
+        // Method, CRC-32, compressed and real size, name length.
+        assert_eq!((u16_at(8), u32_at(14), u32_at(18), u32_at(22), u16_at(26)), (0, 0xCBF43926, 9, 9, 5));
+        assert_eq!(&data[30..44], b"a.txt123456789");
+
+        let central = 44;
+        assert_eq!(u32_at(central), 0x02014b50);
+        assert_eq!((u16_at(central + 10), u32_at(central + 16), u32_at(central + 20), u32_at(central + 24)), (0, 0xCBF43926, 9, 9));
+        // Offset of the local header, then the name.
+        assert_eq!(u32_at(central + 42), 0);
+        assert_eq!(&data[central + 46..central + 51], b"a.txt");
+
+        let end = central + 51;
+        assert_eq!(u32_at(end), 0x06054b50);
+        assert_eq!((u16_at(end + 10), u32_at(end + 12), u32_at(end + 16)), (1, 51, central as u32));
+        assert_eq!(data.len(), end + 22);
+    }
+}